@@ -1,7 +1,10 @@
 use criterion::{Criterion, criterion_group, criterion_main};
-use electron::{Queue, Stack};
+use electron::threadpool::{ThreadPool, ThreadPoolBuilder};
+use electron::{HashMap, Holder, ObjectPool, Queue, SegQueue, Stack};
 use std::collections::LinkedList;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock};
 
 fn std_mutex_stack(threads: usize) {
     let new = &Mutex::new(LinkedList::new());
@@ -53,6 +56,22 @@ fn std_mutex_queue(threads: usize) {
 
 fn electron_queue(threads: usize) {
     let new = &Queue::new();
+    std::thread::scope(|s| {
+        for i in 0..threads {
+            s.spawn(move || {
+                new.enqueue(i).unwrap();
+            });
+        }
+        for _ in 0..threads {
+            s.spawn(move || {
+                let _ = new.dequeue();
+            });
+        }
+    });
+}
+
+fn electron_seg_queue(threads: usize) {
+    let new = &SegQueue::new();
     std::thread::scope(|s| {
         for i in 0..threads {
             s.spawn(move || {
@@ -67,6 +86,127 @@ fn electron_queue(threads: usize) {
     });
 }
 
+fn electron_queue_round_trips(queue: &Queue<usize>, threads: usize) {
+    std::thread::scope(|s| {
+        for i in 0..threads {
+            s.spawn(move || {
+                for j in 0..100 {
+                    queue.enqueue(i * 100 + j).unwrap();
+                    let _ = queue.dequeue();
+                }
+            });
+        }
+    });
+}
+
+// Every thread writes its own keys and then reads all of them back ten times, which is roughly
+// the read heavy mix of a cache.
+fn std_mutex_map(threads: usize) {
+    let new = &Mutex::new(std::collections::HashMap::new());
+    std::thread::scope(|s| {
+        for i in 0..threads {
+            s.spawn(move || {
+                for j in 0..10 {
+                    new.lock().unwrap().insert(i * 10 + j, j);
+                }
+                for _ in 0..10 {
+                    for j in 0..10 {
+                        let _ = new.lock().unwrap().get(&(i * 10 + j)).copied();
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn std_rwlock_map(threads: usize) {
+    let new = &RwLock::new(std::collections::HashMap::new());
+    std::thread::scope(|s| {
+        for i in 0..threads {
+            s.spawn(move || {
+                for j in 0..10 {
+                    new.write().unwrap().insert(i * 10 + j, j);
+                }
+                for _ in 0..10 {
+                    for j in 0..10 {
+                        let _ = new.read().unwrap().get(&(i * 10 + j)).copied();
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn electron_map(threads: usize) {
+    let new = &HashMap::new();
+    std::thread::scope(|s| {
+        for i in 0..threads {
+            s.spawn(move || {
+                let mut holder = Holder::default();
+                for j in 0..10 {
+                    new.insert(i * 10 + j, j);
+                }
+                for _ in 0..10 {
+                    for j in 0..10 {
+                        let _ = new.get(&(i * 10 + j), &mut holder).map(|value| *value);
+                    }
+                }
+            });
+        }
+    });
+}
+
+// Every thread takes a buffer, writes to it and hands it back a hundred times.
+fn std_mutex_pool(pool: &Mutex<Vec<Vec<u8>>>, threads: usize) {
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(move || {
+                for _ in 0..100 {
+                    let mut buffer = pool.lock().unwrap().pop().unwrap_or_else(|| vec![0; 4096]);
+                    buffer[0] = 1;
+                    buffer.fill(0);
+                    pool.lock().unwrap().push(buffer);
+                }
+            });
+        }
+    });
+}
+
+fn electron_pool(pool: &ObjectPool<Vec<u8>>, threads: usize) {
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(move || {
+                for _ in 0..100 {
+                    let mut buffer = pool.get();
+                    buffer[0] = 1;
+                }
+            });
+        }
+    });
+}
+
+// Splits the work in half until a single unit is left, spawning a task for every half, and
+// signals once the last unit is done.
+fn fork(pool: &'static ThreadPool, units: usize, left: Arc<AtomicUsize>, done: Sender<()>) {
+    if units == 1 {
+        if left.fetch_sub(1, Ordering::AcqRel) == 1 {
+            done.send(()).unwrap();
+        }
+        return;
+    }
+    for half in [units / 2, units - units / 2] {
+        let (left, done) = (Arc::clone(&left), done.clone());
+        pool.execute_task(move || fork(pool, half, left, done));
+    }
+}
+
+fn fork_join(pool: &'static ThreadPool, units: usize) {
+    let (done, finished) = mpsc::channel();
+    let left = Arc::new(AtomicUsize::new(units));
+    pool.execute_task(move || fork(pool, units, left, done));
+    finished.recv().unwrap();
+}
+
 macro_rules! generate_stack_benchmark {
     ($name: ident, $number: expr) => {
         fn $name(c: &mut Criterion) {
@@ -84,6 +224,78 @@ macro_rules! generate_queue_benchmark {
             let mut group = c.benchmark_group("Delta");
             group.bench_function("Std_queue", |b| b.iter(|| std_mutex_queue($number)));
             group.bench_function("Electron_queue", |b| b.iter(|| electron_queue($number)));
+            group.bench_function("Electron_seg_queue", |b| {
+                b.iter(|| electron_seg_queue($number))
+            });
+            group.finish();
+        }
+    };
+}
+macro_rules! generate_pool_benchmark {
+    ($name: ident, $number: expr) => {
+        fn $name(c: &mut Criterion) {
+            let plain = Queue::new();
+            let pooled = Queue::with_node_pool(1024);
+            let mut group = c.benchmark_group("Echo");
+            group.bench_function("Electron_queue", |b| {
+                b.iter(|| electron_queue_round_trips(&plain, $number))
+            });
+            group.bench_function("Electron_pooled_queue", |b| {
+                b.iter(|| electron_queue_round_trips(&pooled, $number))
+            });
+            group.finish();
+        }
+    };
+}
+macro_rules! generate_map_benchmark {
+    ($name: ident, $number: expr) => {
+        fn $name(c: &mut Criterion) {
+            let mut group = c.benchmark_group("Foxtrot");
+            group.bench_function("Std_mutex_map", |b| b.iter(|| std_mutex_map($number)));
+            group.bench_function("Std_rwlock_map", |b| b.iter(|| std_rwlock_map($number)));
+            group.bench_function("Electron_map", |b| b.iter(|| electron_map($number)));
+            group.finish();
+        }
+    };
+}
+macro_rules! generate_object_pool_benchmark {
+    ($name: ident, $number: expr) => {
+        fn $name(c: &mut Criterion) {
+            let std_pool = Mutex::new(Vec::new());
+            let pool = ObjectPool::with_max_idle($number, || vec![0; 4096])
+                .with_reset(|buffer: &mut Vec<u8>| buffer.fill(0));
+            let mut group = c.benchmark_group("Golf");
+            group.bench_function("Std_mutex_pool", |b| {
+                b.iter(|| std_mutex_pool(&std_pool, $number))
+            });
+            group.bench_function("Electron_pool", |b| {
+                b.iter(|| electron_pool(&pool, $number))
+            });
+            group.finish();
+        }
+    };
+}
+macro_rules! generate_fork_join_benchmark {
+    ($name: ident, $number: expr) => {
+        fn $name(c: &mut Criterion) {
+            // The tasks borrow the pools for good, so they are leaked.
+            let shared: &'static ThreadPool = Box::leak(Box::new(
+                ThreadPoolBuilder::new().num_threads(4).build().unwrap(),
+            ));
+            let stealing: &'static ThreadPool = Box::leak(Box::new(
+                ThreadPoolBuilder::new()
+                    .num_threads(4)
+                    .work_stealing(true)
+                    .build()
+                    .unwrap(),
+            ));
+            let mut group = c.benchmark_group("Hotel");
+            group.bench_function("Shared_queue_pool", |b| {
+                b.iter(|| fork_join(shared, $number))
+            });
+            group.bench_function("Work_stealing_pool", |b| {
+                b.iter(|| fork_join(stealing, $number))
+            });
             group.finish();
         }
     };
@@ -92,6 +304,14 @@ generate_stack_benchmark!(benchmark1, 10);
 generate_stack_benchmark!(benchmark2, 100);
 generate_queue_benchmark!(benchmark3, 10);
 generate_queue_benchmark!(benchmark4, 100);
+generate_pool_benchmark!(benchmark5, 10);
+generate_pool_benchmark!(benchmark6, 100);
+generate_map_benchmark!(benchmark7, 10);
+generate_map_benchmark!(benchmark8, 100);
+generate_object_pool_benchmark!(benchmark9, 10);
+generate_object_pool_benchmark!(benchmark10, 100);
+generate_fork_join_benchmark!(benchmark11, 1000);
+generate_fork_join_benchmark!(benchmark12, 10000);
 
-criterion_group! {name = benchmarks; config = Criterion::default(); targets = benchmark1, benchmark2, benchmark3, benchmark4}
+criterion_group! {name = benchmarks; config = Criterion::default(); targets = benchmark1, benchmark2, benchmark3, benchmark4, benchmark5, benchmark6, benchmark7, benchmark8, benchmark9, benchmark10, benchmark11, benchmark12}
 criterion_main!(benchmarks);
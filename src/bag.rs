@@ -0,0 +1,249 @@
+use crate::sync::atomic::AtomicPtr;
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use crate::{BoxedPointer, Doer, Holder, ShardedCounter, TaggedPtr};
+
+static DROPBOX: BoxedPointer = BoxedPointer::new();
+
+const BLOCK_SIZE: usize = 32;
+
+// The tag of the next pointer of a block that has been unlinked by its owner.
+const UNLINKED: usize = 1;
+
+// Identifies the bags, so that a thread can cache its slot of the bag it used last.
+static NEXT_BAG: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+// Identifies the threads that own the slots.
+static NEXT_TOKEN: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+thread_local! {
+    static TOKEN: usize = NEXT_TOKEN.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    static CACHED: Cell<(usize, *const ())> = const { Cell::new((usize::MAX, ptr::null())) };
+}
+
+struct Block<T> {
+    items: [AtomicPtr<T>; BLOCK_SIZE],
+    next: TaggedPtr<Block<T>, 1>,
+}
+
+impl<T> Block<T> {
+    fn is_empty(&self) -> bool {
+        self.items
+            .iter()
+            .all(|item| item.load(Ordering::Acquire).is_null())
+    }
+
+    // Takes any value out of the block. Taking an item is a swap, so that exactly one of the
+    // threads that race for it gets it.
+    fn take(&self) -> Option<T> {
+        self.items.iter().rev().find_map(|item| {
+            if item.load(Ordering::Relaxed).is_null() {
+                return None;
+            }
+            let taken = item.swap(ptr::null_mut(), Ordering::AcqRel);
+            (!taken.is_null()).then(|| *unsafe { Box::from_raw(taken) })
+        })
+    }
+}
+
+// The blocks of a single thread. Only the owner adds values and links or unlinks blocks, every
+// thread may take values out of them.
+struct Slot<T> {
+    owner: usize,
+    head: TaggedPtr<Block<T>, 1>,
+    // The next free item in the head block, which only the owner touches.
+    index: UnsafeCell<usize>,
+    next: *mut Slot<T>,
+}
+
+/// An unordered bag of values. Every thread adds to blocks of its own, so adding never contends
+/// with other threads. try_remove_any takes values out of the blocks of the calling thread first
+/// and steals from the blocks of other threads otherwise.
+///
+/// A thread sweeps its drained blocks out of its list whenever it starts a new one. Other threads
+/// might still be looking for values in them, so they are retired through the hazard domain.
+/// The blocks of threads that have exited are only freed along with the bag.
+pub struct Bag<T> {
+    id: usize,
+    slots: AtomicPtr<Slot<T>>,
+    len: ShardedCounter,
+    marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for Bag<T> {}
+unsafe impl<T: Send> Sync for Bag<T> {}
+
+impl<T> Drop for Bag<T> {
+    fn drop(&mut self) {
+        let mut slot = self.slots.load(Ordering::Acquire);
+        while !slot.is_null() {
+            let owned = unsafe { Box::from_raw(slot) };
+            // Blocks that were unlinked have already been retired.
+            let (mut block, _) = owned.head.load(Ordering::Acquire);
+            while !block.is_null() {
+                let owned = unsafe { Box::from_raw(block) };
+                while owned.take().is_some() {}
+                (block, _) = owned.next.load(Ordering::Acquire);
+            }
+            slot = owned.next;
+        }
+    }
+}
+
+impl<T> Default for Bag<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Bag<T> {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_BAG.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            slots: AtomicPtr::new(ptr::null_mut()),
+            len: ShardedCounter::new(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn add(&self, value: T) {
+        let slot = unsafe { &*self.slot() };
+        let index = unsafe { &mut *slot.index.get() };
+        let (mut block, _) = slot.head.load(Ordering::Relaxed);
+        if *index == BLOCK_SIZE {
+            self.sweep(slot);
+            let new = Box::into_raw(Box::new(Block {
+                items: std::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+                next: TaggedPtr::new(block, 0),
+            }));
+            slot.head.store(new, 0, Ordering::Release);
+            block = new;
+            *index = 0;
+        }
+        // Counted before the value is published so that a removal can not take the count below
+        // zero.
+        self.len.increment();
+        let boxed = Box::into_raw(Box::new(value));
+        unsafe { (*block).items[*index].store(boxed, Ordering::Release) };
+        *index += 1;
+    }
+
+    /// Takes any value out of the bag. Returns None if none could be found, which under
+    /// concurrent use can also happen while values are being added.
+    pub fn try_remove_any(&self) -> Option<T> {
+        let own = self.slot();
+        // The blocks of our own slot can only be freed by us, so they need no protection.
+        let (mut block, _) = unsafe { (*own).head.load(Ordering::Acquire) };
+        while !block.is_null() {
+            if let Some(value) = unsafe { (*block).take() } {
+                self.len.sub(1);
+                return Some(value);
+            }
+            (block, _) = unsafe { (*block).next.load(Ordering::Acquire) };
+        }
+        let mut slot = self.slots.load(Ordering::Acquire);
+        while !slot.is_null() {
+            if slot != own
+                && let Some(value) = Self::steal(unsafe { &*slot })
+            {
+                self.len.sub(1);
+                return Some(value);
+            }
+            slot = unsafe { (*slot).next };
+        }
+        None
+    }
+
+    /// Returns the number of values. It is only an approximation under concurrent use.
+    pub fn len(&self) -> usize {
+        self.len.load()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Looks for a value in the blocks of another thread. A block is only looked at after it has
+    // been protected and its predecessor has been found to still be linked, that is its next
+    // pointer has not been tagged as unlinked. Otherwise we start over from the head.
+    fn steal(slot: &Slot<T>) -> Option<T> {
+        let mut pred_holder = Holder::default();
+        let mut curr_holder = Holder::default();
+        'retry: loop {
+            let (mut block, _) = curr_holder.protect_tagged(&slot.head);
+            while !block.is_null() {
+                if let Some(value) = unsafe { (*block).take() } {
+                    return Some(value);
+                }
+                std::mem::swap(&mut pred_holder, &mut curr_holder);
+                let tag;
+                (block, tag) = curr_holder.protect_tagged(unsafe { &(*block).next });
+                if tag == UNLINKED {
+                    continue 'retry;
+                }
+            }
+            return None;
+        }
+    }
+
+    // Unlinks the drained blocks of the calling thread, apart from the head block that it is
+    // still adding to, and retires them.
+    fn sweep(&self, slot: &Slot<T>) {
+        let (mut pred, _) = slot.head.load(Ordering::Relaxed);
+        if pred.is_null() {
+            return;
+        }
+        let (mut block, _) = unsafe { (*pred).next.load(Ordering::Relaxed) };
+        while !block.is_null() {
+            let (next, _) = unsafe { (*block).next.load(Ordering::Relaxed) };
+            if unsafe { (*block).is_empty() } {
+                // Tagged first, so that a thief standing on the block starts over instead of
+                // following it to a block that might be unlinked next.
+                unsafe { (*block).next.store(next, UNLINKED, Ordering::Release) };
+                unsafe { (*pred).next.store(next, 0, Ordering::Release) };
+                let mut holder = Holder::default();
+                let wrapper = unsafe { holder.get_wrapper(&AtomicPtr::new(block), &DROPBOX) };
+                if let Some(mut wrapper) = wrapper {
+                    wrapper.retire();
+                }
+            } else {
+                pred = block;
+            }
+            block = next;
+        }
+    }
+
+    // Returns the slot of the calling thread, creating it on first use.
+    fn slot(&self) -> *mut Slot<T> {
+        let (id, cached) = CACHED.with(|cached| cached.get());
+        if id == self.id {
+            return cached as *mut Slot<T>;
+        }
+        let token = TOKEN.with(|token| *token);
+        let mut slot = self.slots.load(Ordering::Acquire);
+        while !slot.is_null() && unsafe { (*slot).owner } != token {
+            slot = unsafe { (*slot).next };
+        }
+        if slot.is_null() {
+            slot = Box::into_raw(Box::new(Slot {
+                owner: token,
+                head: TaggedPtr::null(),
+                index: UnsafeCell::new(BLOCK_SIZE),
+                next: self.slots.load(Ordering::Acquire),
+            }));
+            while let Err(actual) = self.slots.compare_exchange(
+                unsafe { (*slot).next },
+                slot,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                unsafe { (*slot).next = actual };
+            }
+        }
+        CACHED.with(|cached| cached.set((self.id, slot as *const ())));
+        slot
+    }
+}
@@ -0,0 +1,384 @@
+use crate::Queue;
+use crate::queue::{Closed, DequeueError, Registration};
+use crate::wait::{WaitList, Waiter};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// The error returned by a send once all the receivers are gone. It carries back the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is bounded and has no capacity left.
+    Full(T),
+    /// All the receivers are gone.
+    Disconnected(T),
+}
+
+/// The error returned by a receive once the channel is empty and all the senders are gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Sending on a channel without receivers")
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Sending on a full channel"),
+            TrySendError::Disconnected(_) => f.write_str("Sending on a channel without receivers"),
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Receiving on an empty channel without senders")
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("Receiving on an empty channel"),
+            TryRecvError::Disconnected => {
+                f.write_str("Receiving on an empty channel without senders")
+            }
+        }
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => f.write_str("Timed out while waiting for a value"),
+            RecvTimeoutError::Disconnected => {
+                f.write_str("Receiving on an empty channel without senders")
+            }
+        }
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for SendError<T> {}
+impl<T: fmt::Debug> std::error::Error for TrySendError<T> {}
+impl std::error::Error for RecvError {}
+impl std::error::Error for TryRecvError {}
+impl std::error::Error for RecvTimeoutError {}
+
+// The state shared by all the handles of a channel. The last handle of either side closes the
+// queue, which fails the sends and ends the receives once the queue is drained. The counts of
+// the handles are only ever changed with SeqCst so that a sender that registers itself and then
+// sees receivers left is guaranteed to get notified by the last receiver going away.
+struct Channel<T> {
+    queue: Queue<T>,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    // The capacity of a bounded channel and the number of values that occupy it. The count is
+    // bumped before a value is enqueued and dropped after it has been dequeued, so it may only
+    // be higher than the length of the queue for a moment.
+    capacity: Option<usize>,
+    occupied: AtomicUsize,
+    // The senders waiting for capacity of a bounded channel.
+    senders_waiting: WaitList,
+}
+
+impl<T> Channel<T> {
+    fn disconnected(&self) -> bool {
+        self.senders.load(Ordering::SeqCst) == 0
+    }
+
+    fn abandoned(&self) -> bool {
+        self.receivers.load(Ordering::SeqCst) == 0
+    }
+
+    // Takes up a unit of capacity, which always succeeds for an unbounded channel.
+    fn reserve(&self) -> bool {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return true,
+        };
+        let mut occupied = self.occupied.load(Ordering::SeqCst);
+        loop {
+            if occupied >= capacity {
+                return false;
+            }
+            match self.occupied.compare_exchange(
+                occupied,
+                occupied + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(current) => occupied = current,
+            }
+        }
+    }
+
+    // Gives back a unit of capacity.
+    fn release(&self) {
+        if self.capacity.is_some() {
+            self.occupied.fetch_sub(1, Ordering::SeqCst);
+            self.senders_waiting.notify_one();
+        }
+    }
+
+    fn received(&self, value: T) -> T {
+        self.release();
+        value
+    }
+}
+
+/// Creates a channel without a limit on the number of values in flight. Sends never block.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    with_capacity(None)
+}
+
+/// Creates a channel that holds at most capacity values. Sends block while it is full, which
+/// keeps fast producers from running away from the receivers.
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(
+        capacity > 0,
+        "A bounded channel needs a capacity of at least one"
+    );
+    with_capacity(Some(capacity))
+}
+
+fn with_capacity<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        queue: Queue::new(),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+        capacity,
+        occupied: AtomicUsize::new(0),
+        senders_waiting: WaitList::new(),
+    });
+    (
+        Sender {
+            channel: Arc::clone(&channel),
+        },
+        Receiver { channel },
+    )
+}
+
+/// The sending half of a channel. It can be cloned to send from several threads, the channel
+/// gets disconnected once all of the clones are dropped.
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// The receiving half of a channel. It can be cloned to receive on several threads, every value
+/// is received by exactly one of the clones.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Ordering::SeqCst);
+        Self {
+            channel: Arc::clone(&self.channel),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.channel.queue.close();
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Ordering::SeqCst);
+        Self {
+            channel: Arc::clone(&self.channel),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.channel.receivers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.channel.queue.close();
+            self.channel.senders_waiting.notify(usize::MAX);
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+impl<T> Sender<T> {
+    /// Sends the value, blocking while a bounded channel is full.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let channel = &self.channel;
+        let mut value = value;
+        loop {
+            match self.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(value)) => return Err(SendError(value)),
+                Err(TrySendError::Full(returned)) => {
+                    let waiter = Arc::new(Waiter::new(std::thread::current()));
+                    channel.senders_waiting.register(Arc::clone(&waiter));
+                    if channel.abandoned() || channel.reserve() {
+                        // The capacity might have been freed up before the registration became
+                        // visible, so we check once more instead of waiting for a notification
+                        // that might never come.
+                        if !channel.senders_waiting.cancel(&waiter) {
+                            channel.senders_waiting.notify_one();
+                        }
+                        if channel.abandoned() {
+                            return Err(SendError(returned));
+                        }
+                        return self.enqueue(returned).map_err(|error| match error {
+                            TrySendError::Full(value) | TrySendError::Disconnected(value) => {
+                                SendError(value)
+                            }
+                        });
+                    }
+                    while !waiter.is_notified() {
+                        if channel.abandoned() {
+                            channel.senders_waiting.cancel(&waiter);
+                            break;
+                        }
+                        std::thread::park();
+                    }
+                    value = returned;
+                }
+            }
+        }
+    }
+
+    /// Sends the value if the channel has capacity left.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let channel = &self.channel;
+        if channel.abandoned() {
+            return Err(TrySendError::Disconnected(value));
+        }
+        if !channel.reserve() {
+            return Err(TrySendError::Full(value));
+        }
+        self.enqueue(value)
+    }
+
+    // Enqueues a value for which capacity has been reserved.
+    fn enqueue(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.channel.queue.enqueue(value).map_err(|Closed(value)| {
+            self.channel.release();
+            TrySendError::Disconnected(value)
+        })
+    }
+
+    /// Returns whether all the receivers are gone.
+    pub fn is_disconnected(&self) -> bool {
+        self.channel.abandoned()
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receives a value if there is one.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let channel = &self.channel;
+        match channel.queue.dequeue() {
+            Ok(value) => Ok(channel.received(value)),
+            Err(DequeueError::Empty) => Err(TryRecvError::Empty),
+            Err(DequeueError::ClosedAndEmpty) => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    /// Receives a value, blocking while the channel is empty. Fails once the channel is empty
+    /// and all the senders are gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let channel = &self.channel;
+        channel
+            .queue
+            .dequeue_blocking()
+            .map(|value| channel.received(value))
+            .map_err(|_| RecvError)
+    }
+
+    /// Receives a value, blocking for at most the given duration while the channel is empty.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let channel = &self.channel;
+        match channel.queue.dequeue_timeout(timeout) {
+            Ok(value) => Ok(channel.received(value)),
+            Err(DequeueError::Empty) => Err(RecvTimeoutError::Timeout),
+            Err(DequeueError::ClosedAndEmpty) => Err(RecvTimeoutError::Disconnected),
+        }
+    }
+
+    /// Returns a future that resolves to the next value, or to an error once the channel is
+    /// empty and all the senders are gone.
+    pub fn recv_async(&self) -> RecvFuture<'_, T> {
+        RecvFuture {
+            channel: &self.channel,
+            registration: Registration::new(&self.channel.queue),
+        }
+    }
+
+    /// Returns whether all the senders are gone. There might still be values left to receive.
+    pub fn is_disconnected(&self) -> bool {
+        self.channel.disconnected()
+    }
+
+    /// Returns the number of values in the channel, which is only an approximation under
+    /// concurrent use.
+    pub fn len(&self) -> usize {
+        self.channel.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channel.queue.is_empty()
+    }
+}
+
+/// A future that resolves to the next value of a channel. It is returned by
+/// [`Receiver::recv_async`].
+pub struct RecvFuture<'a, T> {
+    channel: &'a Channel<T>,
+    registration: Registration<'a, T>,
+}
+
+impl<T> Future for RecvFuture<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let channel = this.channel;
+        this.registration
+            .poll(cx)
+            .map(|value| value.map(|value| channel.received(value)).ok_or(RecvError))
+    }
+}
@@ -0,0 +1,55 @@
+use crate::sync::atomic::AtomicUsize;
+use crate::sync::thread;
+use std::sync::atomic::Ordering;
+
+/// Coordinates the thread that moves a value out of a node with the threads that are reading
+/// that value in place. Hazard pointers only keep the memory of the node alive, the value inside
+/// of it is owned by whoever dequeued it the moment the compare_exchange succeeds and it may be
+/// dropped right after, which would leave the readers looking at freed resources.
+///
+/// The compare_exchange that unlinks the node doubles as the mark that the value is taken, so
+/// removing a value costs a single load of the reader count on top of it. A reader registers
+/// itself first and then checks that the node is still linked, while the taker unlinks the node
+/// first and then loads the count. With both sides using SeqCst, either the reader sees the node
+/// unlinked and backs off, or the taker sees the reader and waits for it to leave.
+pub(crate) struct Claim {
+    readers: AtomicUsize,
+}
+
+impl Claim {
+    pub(crate) fn new() -> Self {
+        Self {
+            readers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Registers a reader. Returns None if linked finds that the node has been unlinked in the
+    /// meantime, in which case the reader must not touch the value. linked has to load with
+    /// SeqCst whatever the taker unlinks the node from. The reader leaves when the returned
+    /// handle is dropped, which also covers the closure of the reader panicking.
+    pub(crate) fn enter(&self, linked: impl FnOnce() -> bool) -> Option<Reader<'_>> {
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        let reader = Reader { claim: self };
+        linked().then_some(reader)
+    }
+
+    /// Waits for the readers that got in before the node was unlinked to leave. It has to be
+    /// called after a SeqCst compare_exchange that unlinked the node. This is the only place
+    /// where Queue and Stack block, for as long as a peek closure or a Debug or Clone of the
+    /// value is running.
+    pub(crate) fn take(&self) {
+        while self.readers.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    claim: &'a Claim,
+}
+
+impl Drop for Reader<'_> {
+    fn drop(&mut self) {
+        self.claim.readers.fetch_sub(1, Ordering::Release);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::padded::CachePadded;
+use crate::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+// Hands out the shards to threads round robin, which spreads them out evenly without having to
+// ask the OS which core a thread runs on.
+static NEXT_SHARD: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// A counter split into one cache padded cell per core. Every thread adds to its own cell, so
+/// threads counting at the same time do not fight over a single cache line. Reading the counter
+/// sums up all of the cells, which makes load more expensive than add.
+///
+/// All operations are relaxed. A load that runs concurrently with adds sees some subset of them,
+/// so the counter is meant for statistics rather than for synchronisation.
+pub struct ShardedCounter {
+    cells: Box<[CachePadded<AtomicUsize>]>,
+}
+
+impl Default for ShardedCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for ShardedCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ShardedCounter").field(&self.load()).finish()
+    }
+}
+
+impl ShardedCounter {
+    /// Creates a counter with one cell per available core.
+    pub fn new() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        Self::with_shards(cores)
+    }
+
+    /// Creates a counter with the given number of cells, rounded up to a power of two.
+    pub fn with_shards(shards: usize) -> Self {
+        Self {
+            cells: (0..shards.max(1).next_power_of_two())
+                .map(|_| CachePadded::new(AtomicUsize::new(0)))
+                .collect(),
+        }
+    }
+
+    pub fn add(&self, value: usize) {
+        self.cell().fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    /// Subtracts from the counter. A single cell may wrap around below zero, which cancels out
+    /// once the cells are summed up.
+    pub fn sub(&self, value: usize) {
+        self.cell().fetch_sub(value, Ordering::Relaxed);
+    }
+
+    /// Returns the sum of all cells.
+    pub fn load(&self) -> usize {
+        self.cells.iter().fold(0, |sum, cell| {
+            sum.wrapping_add(cell.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Sets the counter back to zero and returns what it was. Adds that run concurrently are
+    /// either counted in the result or stay in the counter.
+    pub fn reset(&self) -> usize {
+        self.cells.iter().fold(0, |sum, cell| {
+            sum.wrapping_add(cell.swap(0, Ordering::Relaxed))
+        })
+    }
+
+    fn cell(&self) -> &AtomicUsize {
+        let shard = SHARD.with(|shard| *shard);
+        &self.cells[shard & (self.cells.len() - 1)]
+    }
+}
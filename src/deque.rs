@@ -0,0 +1,589 @@
+#![allow(unexpected_cfgs)]
+
+use crate::sync::atomic::{AtomicPtr, AtomicUsize, fence};
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use crate::{BoxedPointer, Doer, Holder};
+
+static DROPBOX: BoxedPointer = BoxedPointer::new();
+
+#[cfg(not(loom))]
+const MIN_CAPACITY: usize = 16;
+
+// Small enough for loom to also go through the growing and retiring of buffers.
+#[cfg(loom)]
+const MIN_CAPACITY: usize = 1;
+
+/// A circular buffer whose capacity is always a power of two. The indices used to access it grow
+/// forever and get wrapped around with a mask.
+struct Buffer<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+}
+
+impl<T> Buffer<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity)
+                .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+                .collect(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.slots[index & (self.capacity() - 1)].get()
+    }
+
+    unsafe fn write(&self, index: usize, value: T) {
+        unsafe { (*self.slot(index)).write(value) };
+    }
+
+    // The value is read without taking ownership of it. Stealers read a slot before they know if
+    // they won the race for it, so the copy must only be assumed to be initialised by the winner.
+    unsafe fn read(&self, index: usize) -> MaybeUninit<T> {
+        unsafe { std::ptr::read_volatile(self.slot(index)) }
+    }
+}
+
+struct Inner<T> {
+    // The next index to steal from. Only ever moves forward.
+    top: AtomicUsize,
+    // The next index to push to. Only the worker writes it.
+    bottom: AtomicUsize,
+    buffer: AtomicPtr<Buffer<T>>,
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let top = self.top.load(Ordering::Relaxed);
+        let bottom = self.bottom.load(Ordering::Relaxed);
+        let buffer = unsafe { Box::from_raw(self.buffer.load(Ordering::Relaxed)) };
+        let mut index = top;
+        while index != bottom {
+            unsafe { (*buffer.slot(index)).assume_init_drop() };
+            index = index.wrapping_add(1);
+        }
+    }
+}
+
+impl<T> Inner<T> {
+    fn len(&self) -> usize {
+        let top = self.top.load(Ordering::Acquire);
+        let bottom = self.bottom.load(Ordering::Acquire);
+        // The worker briefly moves bottom below top while popping from an empty deque.
+        (bottom.wrapping_sub(top) as isize).max(0) as usize
+    }
+}
+
+/// The result of a steal.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Steal<T> {
+    /// The deque was empty.
+    Empty,
+    /// A value was stolen.
+    Success(T),
+    /// The race for the oldest value was lost to another thread and the steal should be retried.
+    Retry,
+}
+
+impl<T> Steal<T> {
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Steal::Empty)
+    }
+
+    pub fn is_retry(&self) -> bool {
+        matches!(self, Steal::Retry)
+    }
+
+    pub fn success(self) -> Option<T> {
+        match self {
+            Steal::Success(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// The owning end of a Chase-Lev work-stealing deque. The worker pushes and pops values at the
+/// bottom of the deque in LIFO order without ever competing with other threads, except for the
+/// very last value which it might have to race a stealer for. Stealers take the oldest values from
+/// the top.
+///
+/// The buffer grows when it is full. Stealers might still be reading from the old buffer after it
+/// has been replaced, so it gets retired to the hazard domain instead of being freed right away.
+pub struct Worker<T> {
+    inner: Arc<Inner<T>>,
+    // A worker can be sent to another thread but never shared, as only one thread may push or pop.
+    marker: PhantomData<Cell<()>>,
+}
+
+unsafe impl<T> Send for Worker<T> where T: Send {}
+
+/// The stealing end of a Chase-Lev work-stealing deque. It can be cloned and shared freely.
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+unsafe impl<T> Send for Stealer<T> where T: Send {}
+unsafe impl<T> Sync for Stealer<T> where T: Send {}
+
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Default for Worker<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Worker<T> {
+    pub fn new() -> Self {
+        let buffer = Box::into_raw(Box::new(Buffer::new(MIN_CAPACITY)));
+        Self {
+            inner: Arc::new(Inner {
+                top: AtomicUsize::new(0),
+                bottom: AtomicUsize::new(0),
+                buffer: AtomicPtr::new(buffer),
+            }),
+            marker: PhantomData,
+        }
+    }
+
+    /// Creates a new stealer for this deque.
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed);
+        let top = inner.top.load(Ordering::Acquire);
+        let mut buffer = inner.buffer.load(Ordering::Relaxed);
+        if bottom.wrapping_sub(top) >= unsafe { (*buffer).capacity() } {
+            buffer = self.grow(top, bottom, buffer);
+        }
+        unsafe { (*buffer).write(bottom, value) };
+        // Publishes the value before the stealers can see the new bottom.
+        fence(Ordering::Release);
+        inner
+            .bottom
+            .store(bottom.wrapping_add(1), Ordering::Relaxed);
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed);
+        if bottom == inner.top.load(Ordering::Relaxed) {
+            return None;
+        }
+        let bottom = bottom.wrapping_sub(1);
+        let buffer = inner.buffer.load(Ordering::Relaxed);
+        inner.bottom.store(bottom, Ordering::Relaxed);
+        // Pairs with the fence in steal. Either the stealer sees the decremented bottom or we see
+        // the top it has moved past our value.
+        fence(Ordering::SeqCst);
+        let top = inner.top.load(Ordering::Relaxed);
+        let remaining = bottom.wrapping_sub(top) as isize;
+        if remaining < 0 {
+            inner
+                .bottom
+                .store(bottom.wrapping_add(1), Ordering::Relaxed);
+            return None;
+        }
+        let value = unsafe { (*buffer).read(bottom) };
+        if remaining > 0 {
+            return Some(unsafe { value.assume_init() });
+        }
+        // This is the last value, so we have to race the stealers for it like one of them.
+        let won = inner
+            .top
+            .compare_exchange(
+                top,
+                top.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            )
+            .is_ok();
+        inner
+            .bottom
+            .store(bottom.wrapping_add(1), Ordering::Relaxed);
+        if won {
+            Some(unsafe { value.assume_init() })
+        } else {
+            None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Copies the values over into a buffer of twice the size and retires the old one.
+    fn grow(&self, top: usize, bottom: usize, old: *mut Buffer<T>) -> *mut Buffer<T> {
+        let capacity = unsafe { (*old).capacity() } * 2;
+        let new = Buffer::new(capacity);
+        let mut index = top;
+        while index != bottom {
+            unsafe { new.write(index, (*old).read(index).assume_init()) };
+            index = index.wrapping_add(1);
+        }
+        let new = Box::into_raw(Box::new(new));
+        let mut holder = Holder::default();
+        if let Some(mut wrapper) = unsafe { holder.swap(&self.inner.buffer, new, &DROPBOX) } {
+            wrapper.retire();
+        }
+        new
+    }
+}
+
+impl<T> Stealer<T> {
+    /// Tries to steal the oldest value from the deque.
+    pub fn steal(&self) -> Steal<T> {
+        let inner = &*self.inner;
+        let top = inner.top.load(Ordering::Acquire);
+        fence(Ordering::SeqCst);
+        let bottom = inner.bottom.load(Ordering::Acquire);
+        if bottom.wrapping_sub(top) as isize <= 0 {
+            return Steal::Empty;
+        }
+        let mut holder = Holder::default();
+        let guard = unsafe {
+            holder
+                .load_pointer(&inner.buffer)
+                .expect("The buffer is never null")
+        };
+        // Even if the buffer has been replaced since, the value at top was copied from it and has
+        // not been taken as long as top has not moved.
+        let value = unsafe { guard.read(top) };
+        if inner
+            .top
+            .compare_exchange(
+                top,
+                top.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return Steal::Retry;
+        }
+        Steal::Success(unsafe { value.assume_init() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// The end of a Deque that an operation works on.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Front,
+    Back,
+}
+
+impl Side {
+    fn opposite(self) -> Self {
+        match self {
+            Side::Front => Side::Back,
+            Side::Back => Side::Front,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Stable,
+    // A node has been pushed onto the given end, but its neighbour does not link to it yet.
+    Pushed(Side),
+}
+
+struct Node<T> {
+    // Taken by the thread that pops the node, the node itself is only freed once it is safe.
+    value: ManuallyDrop<T>,
+    front: AtomicPtr<Node<T>>,
+    back: AtomicPtr<Node<T>>,
+}
+
+impl<T> Node<T> {
+    // The link to the neighbour on the given side.
+    fn link(&self, side: Side) -> &AtomicPtr<Node<T>> {
+        match side {
+            Side::Front => &self.front,
+            Side::Back => &self.back,
+        }
+    }
+}
+
+// The ends of the deque together with its status. An anchor is never changed once it has been
+// published, every operation replaces the whole anchor with a compare_exchange instead.
+struct Anchor<T> {
+    front: *mut Node<T>,
+    back: *mut Node<T>,
+    status: Status,
+}
+
+impl<T> Anchor<T> {
+    fn end(&self, side: Side) -> *mut Node<T> {
+        match side {
+            Side::Front => self.front,
+            Side::Back => self.back,
+        }
+    }
+
+    fn with_end(&self, side: Side, node: *mut Node<T>, status: Status) -> Self {
+        let (front, back) = match side {
+            Side::Front => (node, self.back),
+            Side::Back => (self.front, node),
+        };
+        Self {
+            front,
+            back,
+            status,
+        }
+    }
+}
+
+// The holders for the anchor, the end node and its neighbour.
+#[derive(Default)]
+struct Holders {
+    anchor: Holder,
+    node: Holder,
+    neighbour: Holder,
+}
+
+/// A double-ended queue that any thread can push to and pop from at both ends, after Michael's
+/// "CAS-based lock-free algorithm for shared deques". The ends of the doubly linked list and a
+/// status live in an anchor, which is swapped out as a whole. A push first swings the anchor to
+/// the new node and marks it unstable, then links the old end node to it, and finally marks the
+/// anchor stable again. Every operation that finds an unstable anchor helps to finish the push
+/// first, so the deque never blocks.
+///
+/// Anchors and popped nodes are retired through the hazard domain. A protected anchor can never
+/// be reused, so comparing anchors by address is free of the ABA problem.
+pub struct Deque<T> {
+    anchor: AtomicPtr<Anchor<T>>,
+    len: AtomicUsize,
+    marker: PhantomData<Node<T>>,
+}
+
+unsafe impl<T: Send> Send for Deque<T> {}
+unsafe impl<T: Send> Sync for Deque<T> {}
+
+impl<T> Drop for Deque<T> {
+    fn drop(&mut self) {
+        while self.pop_back().is_some() {}
+        std::mem::drop(unsafe { Box::from_raw(self.anchor.load(Ordering::Acquire)) });
+    }
+}
+
+impl<T> Default for Deque<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Deque<T> {
+    pub fn new() -> Self {
+        let anchor = Anchor {
+            front: ptr::null_mut(),
+            back: ptr::null_mut(),
+            status: Status::Stable,
+        };
+        Self {
+            anchor: AtomicPtr::new(Box::into_raw(Box::new(anchor))),
+            len: AtomicUsize::new(0),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn push_front(&self, value: T) {
+        self.push(Side::Front, value);
+    }
+
+    pub fn push_back(&self, value: T) {
+        self.push(Side::Back, value);
+    }
+
+    pub fn pop_front(&self) -> Option<T> {
+        self.pop(Side::Front)
+    }
+
+    pub fn pop_back(&self) -> Option<T> {
+        self.pop(Side::Back)
+    }
+
+    /// Returns the number of values. It is only an approximation under concurrent use.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let mut holder = Holder::default();
+        let anchor = holder.protect_load(&self.anchor);
+        unsafe { (*anchor).front.is_null() }
+    }
+
+    fn push(&self, side: Side, value: T) {
+        let node = Box::into_raw(Box::new(Node {
+            value: ManuallyDrop::new(value),
+            front: AtomicPtr::new(ptr::null_mut()),
+            back: AtomicPtr::new(ptr::null_mut()),
+        }));
+        // Counted before the node is published so that a pop can not take the count below zero.
+        self.len.fetch_add(1, Ordering::Relaxed);
+        let mut holders = Holders::default();
+        loop {
+            let current = holders.anchor.protect_load(&self.anchor);
+            let anchor = unsafe { &*current };
+            let end = anchor.end(side);
+            if end.is_null() {
+                let new = Anchor {
+                    front: node,
+                    back: node,
+                    status: Status::Stable,
+                };
+                if self.replace(current, new).is_some() {
+                    return;
+                }
+            } else if anchor.status == Status::Stable {
+                unsafe { (*node).link(side.opposite()).store(end, Ordering::Relaxed) };
+                let new = anchor.with_end(side, node, Status::Pushed(side));
+                if let Some(new) = self.replace(current, new) {
+                    // Somebody else might have finished the push and retired the anchor already.
+                    holders.anchor.protect(new);
+                    if self.anchor.load(Ordering::Acquire) == new {
+                        self.stabilize(new, &mut holders);
+                    }
+                    return;
+                }
+            } else {
+                self.stabilize(current, &mut holders);
+            }
+        }
+    }
+
+    fn pop(&self, side: Side) -> Option<T> {
+        let mut holders = Holders::default();
+        let node = loop {
+            let current = holders.anchor.protect_load(&self.anchor);
+            let anchor = unsafe { &*current };
+            let end = anchor.end(side);
+            if end.is_null() {
+                return None;
+            }
+            if anchor.front == anchor.back {
+                let new = Anchor {
+                    front: ptr::null_mut(),
+                    back: ptr::null_mut(),
+                    status: Status::Stable,
+                };
+                if self.replace(current, new).is_some() {
+                    break end;
+                }
+            } else if anchor.status == Status::Stable {
+                // The end node is only known to be in the deque for as long as the anchor is.
+                holders.node.protect(end);
+                if self.anchor.load(Ordering::Acquire) != current {
+                    continue;
+                }
+                let inner = unsafe { (*end).link(side.opposite()).load(Ordering::Acquire) };
+                let new = anchor.with_end(side, inner, Status::Stable);
+                if self.replace(current, new).is_some() {
+                    break end;
+                }
+            } else {
+                self.stabilize(current, &mut holders);
+            }
+        };
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        // Nobody else touches the value of a node that has been popped.
+        let value = unsafe { ManuallyDrop::into_inner(ptr::read(&(*node).value)) };
+        Self::retire(node);
+        Some(value)
+    }
+
+    // Finishes the push that left the anchor unstable by linking the neighbour of the new end
+    // node to it, and marks the anchor stable. The anchor must be protected by the holders. Every
+    // step checks that the anchor is still the current one, as the nodes that it points to are
+    // only known to be in the deque until then.
+    fn stabilize(&self, current: *mut Anchor<T>, holders: &mut Holders) {
+        let anchor = unsafe { &*current };
+        let Status::Pushed(side) = anchor.status else {
+            return;
+        };
+        let node = anchor.end(side);
+        holders.node.protect(node);
+        if self.anchor.load(Ordering::Acquire) != current {
+            return;
+        }
+        let neighbour = unsafe { (*node).link(side.opposite()).load(Ordering::Acquire) };
+        holders.neighbour.protect(neighbour);
+        if self.anchor.load(Ordering::Acquire) != current {
+            return;
+        }
+        let link = unsafe { (*neighbour).link(side) };
+        let linked = link.load(Ordering::Acquire);
+        if linked != node {
+            if self.anchor.load(Ordering::Acquire) != current {
+                return;
+            }
+            if link
+                .compare_exchange(linked, node, Ordering::AcqRel, Ordering::Relaxed)
+                .is_err()
+            {
+                return;
+            }
+        }
+        self.replace(current, anchor.with_end(side, node, Status::Stable));
+    }
+
+    // Swaps the current anchor for a new one and retires the old one. Returns the new anchor, or
+    // None if the current one has been replaced in the meantime.
+    fn replace(&self, current: *mut Anchor<T>, new: Anchor<T>) -> Option<*mut Anchor<T>> {
+        let new = Box::into_raw(Box::new(new));
+        match self
+            .anchor
+            .compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => {
+                Self::retire(current);
+                Some(new)
+            }
+            Err(_) => {
+                std::mem::drop(unsafe { Box::from_raw(new) });
+                None
+            }
+        }
+    }
+
+    fn retire<U>(ptr: *mut U) {
+        let mut holder = Holder::default();
+        let wrapper = unsafe { holder.get_wrapper(&AtomicPtr::new(ptr), &DROPBOX) };
+        if let Some(mut wrapper) = wrapper {
+            wrapper.retire();
+        }
+    }
+}
@@ -0,0 +1,528 @@
+#![allow(unexpected_cfgs)]
+
+use crate::hazard::Guard;
+use crate::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use crate::{BoxedPointer, Doer, Holder};
+
+static DROPBOX: BoxedPointer = BoxedPointer::new();
+
+// Segment 0 holds bucket 0 and every segment i after it holds the buckets from 2^(i - 1) up to
+// 2^i, so the bucket table can double without ever moving a bucket.
+const SEGMENTS: usize = usize::BITS as usize;
+
+const INITIAL_BUCKETS: usize = 2;
+
+// The average number of entries per bucket above which the table doubles.
+const LOAD_FACTOR: usize = 2;
+
+// The lowest bit of a next pointer marks the node that owns the pointer as deleted.
+const MARK: usize = 1;
+
+fn is_marked<T>(ptr: *mut T) -> bool {
+    ptr as usize & MARK == MARK
+}
+
+fn marked<T>(ptr: *mut T) -> *mut T {
+    ptr.map_addr(|addr| addr | MARK)
+}
+
+fn unmarked<T>(ptr: *mut T) -> *mut T {
+    ptr.map_addr(|addr| addr & !MARK)
+}
+
+// The list is sorted by the bit reversed hashes, so that the entries of a bucket stay next to
+// each other no matter how often the table doubles. The highest bit is set for entries before
+// reversing, which keeps them from ever being equal to the key of a bucket's sentinel.
+fn entry_key(hash: usize) -> usize {
+    (hash | 1 << (usize::BITS - 1)).reverse_bits()
+}
+
+fn sentinel_key(bucket: usize) -> usize {
+    bucket.reverse_bits()
+}
+
+// The bucket that a bucket gets split off from when the table doubles.
+fn parent(bucket: usize) -> usize {
+    bucket & !(1 << (usize::BITS - 1 - bucket.leading_zeros()))
+}
+
+fn segment_of(bucket: usize) -> (usize, usize) {
+    match bucket {
+        0 => (0, 0),
+        _ => {
+            let segment = (usize::BITS - bucket.leading_zeros()) as usize;
+            (segment, bucket - (1 << (segment - 1)))
+        }
+    }
+}
+
+fn segment_len(segment: usize) -> usize {
+    match segment {
+        0 => 1,
+        _ => 1 << (segment - 1),
+    }
+}
+
+struct Node<K, V> {
+    key: usize,
+    // Sentinels that start a bucket have no entry.
+    entry: Option<(K, V)>,
+    next: AtomicPtr<Node<K, V>>,
+}
+
+impl<K, V> Node<K, V> {
+    fn matches<Q>(&self, key: usize, entry: Option<&Q>) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        self.key == key
+            && match (&self.entry, entry) {
+                (Some((k, _)), Some(q)) => k.borrow() == q,
+                (None, None) => true,
+                _ => false,
+            }
+    }
+}
+
+// The holders that keep the predecessor and the current node of a search protected.
+#[derive(Default)]
+struct Holders {
+    pred: Holder,
+    curr: Holder,
+}
+
+// Where a search ended. The current node is either the one that was searched for or the one
+// that it has to be inserted in front of.
+struct Position<K, V> {
+    pred: *mut Node<K, V>,
+    curr: *mut Node<K, V>,
+    found: bool,
+}
+
+/// A concurrent hash map built on a split-ordered list, as described by Shalev and Shavit. All
+/// entries live in a single Harris-Michael list sorted by their bit reversed hashes, and every
+/// bucket is a pointer to a sentinel node inside of that list. Doubling the table only adds
+/// buckets, which get their sentinels linked in lazily the first time that they are used, so the
+/// map resizes incrementally without ever moving an entry.
+///
+/// Removed nodes are retired through the hazard domain, which also lets get hand out a guard to
+/// the value instead of a copy of it. The entries of retired nodes get dropped by whichever
+/// thread happens to reclaim them, at some later point, which is why keys and values have to be
+/// Send and 'static.
+pub struct HashMap<K, V, S = RandomState> {
+    segments: [AtomicPtr<AtomicPtr<Node<K, V>>>; SEGMENTS],
+    buckets: AtomicUsize,
+    len: AtomicUsize,
+    hasher: S,
+    marker: PhantomData<Node<K, V>>,
+}
+
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Send> Send for HashMap<K, V, S> {}
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Sync> Sync for HashMap<K, V, S> {}
+
+impl<K, V, S> Drop for HashMap<K, V, S> {
+    fn drop(&mut self) {
+        // Nodes that are still linked have not been retired, even the marked ones.
+        let head = self.segments[0].load(Ordering::Acquire);
+        let mut current = unsafe { (*head).load(Ordering::Acquire) };
+        while !current.is_null() {
+            let owned = unsafe { Box::from_raw(current) };
+            current = unmarked(owned.next.load(Ordering::Acquire));
+        }
+        for (segment, slots) in self.segments.iter().enumerate() {
+            let slots = slots.load(Ordering::Acquire);
+            if !slots.is_null() {
+                let slots = ptr::slice_from_raw_parts_mut(slots, segment_len(segment));
+                std::mem::drop(unsafe { Box::from_raw(slots) });
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Send + 'static, V: Send + 'static> Default for HashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Send + 'static, V: Send + 'static> HashMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_hasher(RandomState::new())
+    }
+}
+
+impl<K, V, S> HashMap<K, V, S>
+where
+    K: Hash + Eq + Send + 'static,
+    V: Send + 'static,
+    S: BuildHasher,
+{
+    pub fn with_hasher(hasher: S) -> Self {
+        let map = Self {
+            segments: std::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+            buckets: AtomicUsize::new(INITIAL_BUCKETS),
+            len: AtomicUsize::new(0),
+            hasher,
+            marker: PhantomData,
+        };
+        let head = Box::into_raw(Box::new(Node {
+            key: sentinel_key(0),
+            entry: None,
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        map.slot(0).store(head, Ordering::Release);
+        map
+    }
+
+    /// Inserts the entry, replacing the one with the same key if there is any. Returns whether
+    /// the key was absent.
+    ///
+    /// A replaced entry is not handed back, because guards obtained through get might still be
+    /// reading it. It gets dropped once it has been reclaimed.
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let hash = self.hash(&key);
+        let start = self.bucket(hash);
+        let node_key = entry_key(hash);
+        let node = Box::into_raw(Box::new(Node {
+            key: node_key,
+            entry: Some((key, value)),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        let key = unsafe { &(*node).entry.as_ref().unwrap_unchecked().0 };
+        let mut holders = Holders::default();
+        loop {
+            let position = self.find(start, node_key, Some(key), &mut holders);
+            if position.found {
+                // The old node gets marked with the new one as its successor, so that the entry
+                // is replaced in a single step and the key is never missing in between.
+                let curr = position.curr;
+                let succ = unsafe { (*curr).next.load(Ordering::Acquire) };
+                if is_marked(succ) {
+                    continue;
+                }
+                unsafe { (*node).next.store(succ, Ordering::Relaxed) };
+                if unsafe { &(*curr).next }
+                    .compare_exchange(succ, marked(node), Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    self.unlink(start, &position, node, &mut holders);
+                    return false;
+                }
+                continue;
+            }
+            if self.link(&position, node) {
+                self.grow();
+                return true;
+            }
+        }
+    }
+
+    /// Returns a guard to the value of the key. The entry stays readable through the guard even
+    /// if it gets removed or replaced in the meantime.
+    pub fn get<'a, Q>(&'a self, key: &Q, holder: &'a mut Holder) -> Option<Guard<'a, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hash(key);
+        let start = self.bucket(hash);
+        let mut holders = Holders::default();
+        let position = self.find(start, entry_key(hash), Some(key), &mut holders);
+        if !position.found {
+            return None;
+        }
+        // Handing over the hazard itself keeps the node protected without a gap.
+        std::mem::swap(holder, &mut holders.curr);
+        Some(Self::value(unsafe { holder.guard(position.curr) }))
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut holder = Holder::default();
+        self.get(key, &mut holder).is_some()
+    }
+
+    /// Returns a guard to the value of the key, inserting the value computed by the closure if
+    /// the key is absent. The closure might get called even though another thread inserts the
+    /// key first, in which case its value is dropped and the other one is returned.
+    pub fn compute_if_absent<'a, F>(&'a self, key: K, f: F, holder: &'a mut Holder) -> Guard<'a, V>
+    where
+        F: FnOnce(&K) -> V,
+    {
+        let hash = self.hash(&key);
+        let start = self.bucket(hash);
+        let node_key = entry_key(hash);
+        let mut holders = Holders::default();
+        let mut position = self.find(start, node_key, Some(&key), &mut holders);
+        if !position.found {
+            let value = f(&key);
+            let node = Box::into_raw(Box::new(Node {
+                key: node_key,
+                entry: Some((key, value)),
+                next: AtomicPtr::new(ptr::null_mut()),
+            }));
+            // Protected before it is published, so that it can not be removed and reclaimed
+            // before we hand out the guard.
+            holder.protect(node);
+            let key = unsafe { &(*node).entry.as_ref().unwrap_unchecked().0 };
+            loop {
+                if self.link(&position, node) {
+                    self.grow();
+                    return Self::value(unsafe { holder.guard(node) });
+                }
+                position = self.find(start, node_key, Some(key), &mut holders);
+                if position.found {
+                    std::mem::drop(unsafe { Box::from_raw(node) });
+                    break;
+                }
+            }
+        }
+        std::mem::swap(holder, &mut holders.curr);
+        Self::value(unsafe { holder.guard(position.curr) })
+    }
+
+    /// Removes the entry of the key. Returns whether there was one.
+    pub fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hash(key);
+        let start = self.bucket(hash);
+        let node_key = entry_key(hash);
+        let mut holders = Holders::default();
+        loop {
+            let position = self.find(start, node_key, Some(key), &mut holders);
+            if !position.found {
+                return false;
+            }
+            let curr = position.curr;
+            let succ = unsafe { (*curr).next.load(Ordering::Acquire) };
+            if is_marked(succ) {
+                // Somebody else is removing or replacing the entry.
+                continue;
+            }
+            if unsafe { &(*curr).next }
+                .compare_exchange(succ, marked(succ), Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                self.unlink(start, &position, succ, &mut holders);
+                return true;
+            }
+        }
+    }
+
+    /// Returns the number of entries. It is only an approximation under concurrent use.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn value(guard: Guard<'_, Node<K, V>>) -> Guard<'_, V> {
+        guard.map(|node| &node.entry.as_ref().expect("Sentinels are never found").1)
+    }
+
+    fn hash<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        self.hasher.hash_one(key) as usize
+    }
+
+    // Links a new node in front of the current node of the position. Only entries are counted,
+    // and they are counted before they get published so that a remove can not take the count
+    // below zero.
+    fn link(&self, position: &Position<K, V>, node: *mut Node<K, V>) -> bool {
+        let entry = unsafe { (*node).entry.is_some() };
+        unsafe { (*node).next.store(position.curr, Ordering::Relaxed) };
+        if entry {
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+        let linked = unsafe { &(*position.pred).next }
+            .compare_exchange(position.curr, node, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok();
+        if entry && !linked {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        linked
+    }
+
+    // Unlinks a node that has just been marked. If that fails, a search from the start of the
+    // bucket unlinks it on the way.
+    fn unlink(
+        &self,
+        start: *mut Node<K, V>,
+        position: &Position<K, V>,
+        succ: *mut Node<K, V>,
+        holders: &mut Holders,
+    ) {
+        if unsafe { &(*position.pred).next }
+            .compare_exchange(position.curr, succ, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            Self::retire(position.curr);
+        } else {
+            let key = unsafe { (*position.curr).key };
+            self.find::<K>(start, key, None, holders);
+        }
+    }
+
+    // Doubles the number of buckets once the entries outgrow them. The new buckets are only
+    // initialised when they are first used.
+    fn grow(&self) {
+        let buckets = self.buckets.load(Ordering::Relaxed);
+        if self.len() > buckets * LOAD_FACTOR && buckets < 1 << (SEGMENTS - 1) {
+            let _ = self.buckets.compare_exchange(
+                buckets,
+                buckets * 2,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    fn slot(&self, bucket: usize) -> &AtomicPtr<Node<K, V>> {
+        let (segment, offset) = segment_of(bucket);
+        let mut slots = self.segments[segment].load(Ordering::Acquire);
+        if slots.is_null() {
+            let allocated: Box<[AtomicPtr<Node<K, V>>]> = (0..segment_len(segment))
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect();
+            let allocated = Box::into_raw(allocated) as *mut AtomicPtr<Node<K, V>>;
+            match self.segments[segment].compare_exchange(
+                ptr::null_mut(),
+                allocated,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => slots = allocated,
+                Err(actual) => {
+                    let allocated = ptr::slice_from_raw_parts_mut(allocated, segment_len(segment));
+                    std::mem::drop(unsafe { Box::from_raw(allocated) });
+                    slots = actual;
+                }
+            }
+        }
+        unsafe { &*slots.add(offset) }
+    }
+
+    // Returns the sentinel of the bucket of the hash, linking it in first if needed. Sentinels
+    // are never removed, so they can be used without protecting them.
+    fn bucket(&self, hash: usize) -> *mut Node<K, V> {
+        let bucket = hash & (self.buckets.load(Ordering::Relaxed) - 1);
+        self.sentinel(bucket)
+    }
+
+    fn sentinel(&self, bucket: usize) -> *mut Node<K, V> {
+        let slot = self.slot(bucket);
+        let sentinel = slot.load(Ordering::Acquire);
+        if !sentinel.is_null() {
+            return sentinel;
+        }
+        // The new sentinel is linked in after the sentinel of the bucket that it is split off
+        // from, and the threads racing to do so agree on whichever one made it into the list.
+        let start = self.sentinel(parent(bucket));
+        let key = sentinel_key(bucket);
+        let node = Box::into_raw(Box::new(Node {
+            key,
+            entry: None,
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        let mut holders = Holders::default();
+        let sentinel = loop {
+            let position = self.find::<K>(start, key, None, &mut holders);
+            if position.found {
+                std::mem::drop(unsafe { Box::from_raw(node) });
+                break position.curr;
+            }
+            if self.link(&position, node) {
+                break node;
+            }
+        };
+        slot.store(sentinel, Ordering::Release);
+        sentinel
+    }
+
+    // Searches the list from the given sentinel for the node with the given key, or for the
+    // first node after where it would be, and unlinks the marked nodes on the way. A node is
+    // only dereferenced after it has been protected and found to be linked to an unmarked
+    // predecessor, otherwise the search starts over as in Michael's version of the Harris list.
+    //
+    // The predecessor and the current node stay protected in the holders until the next search.
+    fn find<Q>(
+        &self,
+        start: *mut Node<K, V>,
+        key: usize,
+        entry: Option<&Q>,
+        holders: &mut Holders,
+    ) -> Position<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        'retry: loop {
+            let mut pred = start;
+            let mut curr = unsafe { (*pred).next.load(Ordering::Acquire) };
+            loop {
+                if is_marked(curr) {
+                    // The predecessor is being removed. Sentinels never are.
+                    continue 'retry;
+                }
+                if curr.is_null() {
+                    return Position {
+                        pred,
+                        curr,
+                        found: false,
+                    };
+                }
+                holders.curr.protect(curr);
+                if unsafe { (*pred).next.load(Ordering::Acquire) } != curr {
+                    continue 'retry;
+                }
+                let succ = unsafe { (*curr).next.load(Ordering::Acquire) };
+                if is_marked(succ) {
+                    if unsafe { &(*pred).next }
+                        .compare_exchange(curr, unmarked(succ), Ordering::AcqRel, Ordering::Relaxed)
+                        .is_err()
+                    {
+                        continue 'retry;
+                    }
+                    Self::retire(curr);
+                    curr = unmarked(succ);
+                    continue;
+                }
+                let node = unsafe { &*curr };
+                if node.key > key || node.matches(key, entry) {
+                    return Position {
+                        pred,
+                        curr,
+                        found: node.key == key,
+                    };
+                }
+                pred = curr;
+                std::mem::swap(&mut holders.pred, &mut holders.curr);
+                curr = succ;
+            }
+        }
+    }
+
+    fn retire(node: *mut Node<K, V>) {
+        let mut holder = Holder::default();
+        let wrapper = unsafe { holder.get_wrapper(&AtomicPtr::new(node), &DROPBOX) };
+        if let Some(mut wrapper) = wrapper {
+            wrapper.retire();
+        }
+    }
+}
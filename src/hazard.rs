@@ -1,10 +1,11 @@
 #![allow(unexpected_cfgs)]
 
-use crate::sync::atomic::{AtomicBool, AtomicPtr};
+use crate::sync::atomic::{AtomicBool, AtomicPtr, fence};
+use crate::tagged::TaggedPtr;
 use std::collections::HashSet;
 use std::convert::AsRef;
 use std::marker::PhantomData;
-use std::ops::{Deref, DerefMut};
+use std::ops::{Deref, DerefMut, Index, IndexMut};
 use std::ptr::NonNull;
 use std::sync::atomic::Ordering;
 
@@ -30,15 +31,42 @@ pub(crate) static SHARED_DOMAIN: GlobalDomain = GlobalDomain {
     },
 };
 
+/// The low bits of a pointer to T that are always zero due to its alignment. Data structures use
+/// them to tag their pointers.
+pub(crate) const fn tag_mask<T>() -> usize {
+    std::mem::align_of::<T>() - 1
+}
+
+pub(crate) fn untagged<T>(ptr: *mut T) -> *mut T {
+    ptr.map_addr(|addr| addr & !tag_mask::<T>())
+}
+
 #[derive(Default)]
 pub struct Holder(Option<&'static Hazard>);
 
+/// Keeps a loaded pointer protected. It only gives out shared references, as other threads
+/// might be reading the same value at the same time.
 pub struct Guard<'a, T> {
     hazptr: &'static Hazard,
     pub(crate) data: *mut T,
     _marker: PhantomData<&'a T>,
 }
 
+impl<'a, T> Guard<'a, T> {
+    /// Turns the guard into one for a part of the protected value, such as one of its fields.
+    /// The whole value stays protected until the new guard gets dropped.
+    pub fn map<U>(self, f: impl FnOnce(&T) -> &U) -> Guard<'a, U> {
+        let guard = std::mem::ManuallyDrop::new(self);
+        // The new guard only ever hands out shared references to the part, like this one.
+        let data = f(unsafe { &*guard.data }) as *const U as *mut U;
+        Guard {
+            hazptr: guard.hazptr,
+            data,
+            _marker: PhantomData,
+        }
+    }
+}
+
 /// SAFETY:
 ///    The caller must make sure that the reference is not used
 ///    after the underlying thing has been deallocated as the
@@ -62,65 +90,48 @@ impl<T> Deref for Guard<'_, T> {
     }
 }
 
-/// SAFETY:
-///  This method can cause safety issues so it must be handled with care.
-///  If two threads deref_mut the guard to the same underlying T we will
-///  then have two mutable pointers to the same thing. If they are used to
-///  read or write at the same time, we will run into undefined behaviour.
-///  Further the lifetime of the mutable reference given out is unbounded.
-///  So it must be handled with care.
-impl<T> DerefMut for Guard<'_, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut (*self.data) }
-    }
-}
-
+// Dropping the guard only clears the protection. The hazard itself stays with the holder
+// so that the holder can be reused for further loads, and it is handed back to the domain
+// when the holder gets dropped.
 impl<T> Drop for Guard<'_, T> {
     fn drop(&mut self) {
         self.hazptr
             .ptr
             .store(std::ptr::null_mut(), Ordering::Release);
-        self.hazptr.flag.store(true, Ordering::Release);
+    }
+}
+
+impl Drop for Holder {
+    fn drop(&mut self) {
+        if let Some(hazptr) = self.0.take() {
+            hazptr.ptr.store(std::ptr::null_mut(), Ordering::Release);
+            hazptr.flag.store(true, Ordering::Release);
+        }
     }
 }
 
 impl Holder {
-    /// SAFETY:
+    /// # Safety
     ///   1. The user must pass a valid pointer. Passing in invalid pointers such as a misaligned
     ///      one will cause undefined behaviour.
     ///   2. If a null pointer is passed that will be taken care of by the implementation as we
     ///      have made sure using NonNull that it does not get dereferenced.
+    ///   3. The pointer may carry a tag in the low bits that are always zero due to the alignment
+    ///      of T. The tag is masked off, both for the protection and for the guard.
     pub unsafe fn load_pointer<'a, T>(&'a mut self, ptr: &'_ AtomicPtr<T>) -> Option<Guard<'a, T>> {
-        let hazptr = if let Some(t) = self.0 {
-            t
+        let loaded = untagged(self.protect_load(ptr));
+        if NonNull::new(loaded).is_some() {
+            Some(Guard {
+                hazptr: self.hazard(),
+                data: loaded,
+                _marker: PhantomData,
+            })
         } else {
-            let ptr = SHARED_DOMAIN.acquire();
-            self.0 = Some(ptr);
-            ptr
-        };
-        let mut ptr1 = ptr.load(Ordering::Acquire);
-        let ret = loop {
-            hazptr.protect(ptr1 as *mut ());
-            let ptr2 = ptr.load(Ordering::Acquire);
-            if ptr1 == ptr2 {
-                if NonNull::new(ptr1).is_some() {
-                    let data = ptr1;
-                    break Some(Guard {
-                        hazptr: &hazptr,
-                        data: data,
-                        _marker: PhantomData,
-                    });
-                } else {
-                    break None;
-                }
-            } else {
-                ptr1 = ptr2;
-            }
-        };
-        return ret;
+            None
+        }
     }
 
-    ///SAFETY:
+    /// # Safety
     ///  1. Swap ensures that the old pointer gets retired. The user must make sure that similar to
     ///     the load method, a valid pointer is passed failing which will cause undefined
     ///     behaviour.
@@ -135,18 +146,18 @@ impl Holder {
     ) -> Option<DoerWrapper<'_, T>> {
         let current = atomic.swap(ptr, Ordering::AcqRel);
         if current.is_null() {
-            return None;
+            None
         } else {
             let wrapper = DoerWrapper {
                 inner: current,
                 domain: &SHARED_DOMAIN,
-                deleter: deleter,
+                deleter,
             };
-            return Some(wrapper);
+            Some(wrapper)
         }
     }
 
-    ///SAFETY:
+    /// # Safety
     ///  1. This method provides a way to get the wrapper to call the retire method if the user is
     ///     not relying on swap. It must be used with care as repeatedly using load without
     ///     using this method and calling retire on it will lead to memory leaks.
@@ -157,14 +168,94 @@ impl Holder {
     ) -> Option<DoerWrapper<'_, T>> {
         let current = atomic.swap(std::ptr::null_mut(), Ordering::AcqRel);
         if current.is_null() {
-            return None;
+            None
         } else {
             let wrapper = DoerWrapper {
                 inner: current,
                 domain: &SHARED_DOMAIN,
-                deleter: deleter,
+                deleter,
             };
-            return Some(wrapper);
+            Some(wrapper)
+        }
+    }
+
+    /// Works like load_pointer for a tagged pointer. The untagged address is protected and
+    /// validated, and the tag is returned along with the guard, as it was read last.
+    ///
+    /// # Safety
+    ///   The same requirements as for load_pointer apply to the untagged address.
+    pub unsafe fn load_tagged<'a, T, const BITS: usize>(
+        &'a mut self,
+        ptr: &'_ TaggedPtr<T, BITS>,
+    ) -> (Option<Guard<'a, T>>, usize) {
+        let (loaded, tag) = self.protect_tagged(ptr);
+        if NonNull::new(loaded).is_some() {
+            let guard = Guard {
+                hazptr: self.hazard(),
+                data: loaded,
+                _marker: PhantomData,
+            };
+            (Some(guard), tag)
+        } else {
+            (None, tag)
+        }
+    }
+
+    /// The same as protect_load for a tagged pointer, with the address and the tag returned
+    /// separately.
+    pub(crate) fn protect_tagged<T, const BITS: usize>(
+        &mut self,
+        ptr: &'_ TaggedPtr<T, BITS>,
+    ) -> (*mut T, usize) {
+        TaggedPtr::<T, BITS>::unpack(self.protect_load(ptr.as_atomic()))
+    }
+
+    /// Loads the pointer and protects the address that it points to, retrying until the address
+    /// is the same before and after the protection. The tag of the pointer is ignored for this
+    /// comparison, so a tag that changes in between does not cause a retry. The pointer is
+    /// returned as it was read last, tag included.
+    pub(crate) fn protect_load<T>(&mut self, ptr: &'_ AtomicPtr<T>) -> *mut T {
+        let hazptr = self.hazard();
+        let mut ptr1 = ptr.load(Ordering::Acquire);
+        loop {
+            hazptr.protect(untagged(ptr1) as *mut ());
+            let ptr2 = ptr.load(Ordering::Acquire);
+            if untagged(ptr1) == untagged(ptr2) {
+                break ptr2;
+            }
+            ptr1 = ptr2;
+        }
+    }
+
+    /// Protects the given pointer without validating it against the location it was read from.
+    /// The caller has to re-read that location afterwards and make sure that the pointer is still
+    /// reachable before dereferencing it. The protection lasts until the next call to protect or
+    /// load_pointer on this holder or until the holder gets dropped.
+    pub(crate) fn protect<T>(&mut self, ptr: *mut T) {
+        self.hazard().protect(ptr as *mut ());
+    }
+
+    /// Hands out a guard for a pointer that is already protected by this holder, for example
+    /// through protect followed by a validation.
+    ///
+    /// # Safety
+    ///   The pointer must be protected by this holder and must not have been retired before it
+    ///   got protected.
+    pub(crate) unsafe fn guard<T>(&mut self, ptr: *mut T) -> Guard<'_, T> {
+        Guard {
+            hazptr: self.hazard(),
+            data: ptr,
+            _marker: PhantomData,
+        }
+    }
+
+    fn hazard(&mut self) -> &'static Hazard {
+        if let Some(t) = self.0 {
+            t
+        } else {
+            let ptr = SHARED_DOMAIN.acquire();
+            self.0 = Some(ptr);
+            ptr
         }
     }
 
@@ -180,6 +271,41 @@ impl Holder {
     }
 }
 
+/// A fixed number of holders for searches that have to keep several pointers protected at the
+/// same time, such as the predecessors and successors on every level of a skiplist. Every slot
+/// acquires its hazard lazily, so unused slots cost nothing.
+pub struct HolderArray<const N: usize>([Holder; N]);
+
+impl<const N: usize> Default for HolderArray<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> HolderArray<N> {
+    pub fn new() -> Self {
+        Self(std::array::from_fn(|_| Holder::default()))
+    }
+
+    /// Swaps the holders in two slots along with the protections that they hold.
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.0.swap(a, b);
+    }
+}
+
+impl<const N: usize> Index<usize> for HolderArray<N> {
+    type Output = Holder;
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.0[index]
+    }
+}
+
+impl<const N: usize> IndexMut<usize> for HolderArray<N> {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.0[index]
+    }
+}
+
 pub(crate) struct Hazard {
     ptr: AtomicPtr<()>,
     next: AtomicPtr<Hazard>,
@@ -189,11 +315,14 @@ pub(crate) struct Hazard {
 impl Hazard {
     pub fn protect(&self, ptr: *mut ()) {
         self.ptr.store(ptr, Ordering::Release);
+        // Pairs with the fence in reclaim. Either the reclaimer observes this protection or
+        // the validating load that follows observes the pointer having been swapped out.
+        fence(Ordering::SeqCst);
     }
 }
 
 pub trait Doer {
-    fn domain<'a>(&'a self) -> &'a GlobalDomain;
+    fn domain(&self) -> &GlobalDomain;
     fn retire(&mut self);
 }
 
@@ -217,25 +346,35 @@ impl<T> DerefMut for DoerWrapper<'_, T> {
 }
 
 impl<T> Doer for DoerWrapper<'_, T> {
-    fn domain<'a>(&'a self) -> &'a GlobalDomain {
+    fn domain(&self) -> &GlobalDomain {
         self.domain
     }
 
     ///SAFETY:
     ///  The user must make sure that a retired pointer is not retired again.
+    ///  The deleter runs inside of whichever reclaim first finds the pointer unprotected, which
+    ///  can happen on any thread and long after the caller returned. Everything that it drops
+    ///  therefore has to be Send and 'static, values that are not must be moved out before the
+    ///  pointer is retired.
     fn retire(&mut self) {
         if self.inner.is_null() {
             let domain = self.domain();
             unsafe {
-                (&domain.ret).reclaim(&domain.list);
+                domain.ret.reclaim(&domain.list);
             }
             return;
         }
         let domain = self.domain();
-        let mut current = (&domain.ret.head).load(Ordering::Acquire);
+        // The retired list lives in a static domain and therefore can only store trait objects
+        // with a 'static bound. The lifetime is erased here, which relies on the requirement
+        // above: the deleter may drop the values left inside of the allocation, so the data
+        // structures either move them out first or only accept Send and 'static values.
+        let erased: *mut (dyn Uniform + '_) = self.inner;
+        let erased: *mut dyn Uniform = unsafe { std::mem::transmute(erased) };
+        let mut current = domain.ret.head.load(Ordering::Acquire);
         loop {
             let ret = Retired {
-                ptr: self.inner as *mut dyn Uniform,
+                ptr: erased,
                 next: AtomicPtr::new(std::ptr::null_mut()),
                 deleter: self.deleter,
             };
@@ -248,11 +387,11 @@ impl<T> Doer for DoerWrapper<'_, T> {
                 .is_err()
             {
                 let drop = unsafe { Box::from_raw(boxed) };
-                current = (&domain.ret.head).load(Ordering::Acquire);
+                current = domain.ret.head.load(Ordering::Acquire);
                 std::mem::drop(drop);
             } else {
                 unsafe {
-                    (&domain.ret).reclaim(&domain.list);
+                    domain.ret.reclaim(&domain.list);
                 }
                 break;
             }
@@ -335,6 +474,7 @@ pub trait Deleter {
 ///      lifetime because we never know when the delete method on that deleter will be called.
 ///      Using static does not come with any memory overhead as the underlying type would be a zero
 ///      sized type.
+#[derive(Default)]
 pub struct BoxedPointer;
 
 impl BoxedPointer {
@@ -344,14 +484,16 @@ impl BoxedPointer {
 }
 
 impl Deleter for BoxedPointer {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     fn delete(&self, ptr: *mut dyn Uniform) {
-        if let Some(_) = NonNull::new(ptr) {
+        if !ptr.is_null() {
             let drop = unsafe { Box::from_raw(ptr) };
             std::mem::drop(drop);
         }
     }
 }
 
+#[derive(Default)]
 pub struct DropPointer;
 
 impl DropPointer {
@@ -361,8 +503,9 @@ impl DropPointer {
 }
 
 impl Deleter for DropPointer {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     fn delete(&self, ptr: *mut dyn Uniform) {
-        if let Some(_) = NonNull::new(ptr) {
+        if !ptr.is_null() {
             unsafe {
                 std::ptr::drop_in_place(ptr);
             }
@@ -376,6 +519,14 @@ impl RetiredList {
     ///    pointers contaning two similar pointers as this will lead to the same pointers being
     ///    dereferenced leading to undefined behaviour.
     unsafe fn reclaim(&self, domain: &HazardList) {
+        // The retired pointers have to be taken before the hazards are scanned. A pointer that
+        // gets retired after the scan could otherwise be freed even though a reader protected
+        // it after the scan and validated it before it was unlinked.
+        let mut swapped = self.head.swap(std::ptr::null_mut(), Ordering::AcqRel);
+        if swapped.is_null() {
+            return;
+        }
+        fence(Ordering::SeqCst);
         let mut set = HashSet::new();
         let mut current = domain.head.load(Ordering::Acquire);
         while !current.is_null() {
@@ -384,15 +535,15 @@ impl RetiredList {
             current = unsafe { &(*current).next }.load(Ordering::Acquire);
         }
         let mut remaining: *mut Retired = std::ptr::null_mut();
-        let mut swapped = self.head.swap(std::ptr::null_mut(), Ordering::AcqRel);
         while !swapped.is_null() {
             let check = unsafe { (*swapped).ptr };
             if !set.contains(&(check as *mut ())) {
                 let deleter = unsafe { (*swapped).deleter };
                 deleter.delete(check);
+                let next = unsafe { ((*swapped).next).load(Ordering::Acquire) };
                 let drop = unsafe { Box::from_raw(swapped) };
                 std::mem::drop(drop);
-                swapped = unsafe { ((*swapped).next).load(Ordering::Acquire) };
+                swapped = next;
             } else {
                 let next = unsafe { ((*swapped).next).load(Ordering::Acquire) };
                 unsafe {
@@ -404,6 +555,9 @@ impl RetiredList {
         }
         // we also need to make sure that we take care of all the pointers that have been retired
         // in the meantime..therefore I came up with this solution
+        if remaining.is_null() {
+            return;
+        }
         let mut safety_variable = remaining;
         loop {
             if self
@@ -1,10 +1,35 @@
+pub mod bag;
+pub mod channel;
+mod claim;
+pub mod counter;
+pub mod deque;
+pub mod hashmap;
 pub mod hazard;
+pub mod listset;
+pub mod padded;
+pub mod pool;
+pub mod priorityqueue;
 pub mod queue;
 mod runtime;
+pub mod segqueue;
+pub mod skipmap;
 pub mod stack;
 pub mod sync;
+pub mod tagged;
 pub mod threadpool;
+mod wait;
 
-pub use crate::hazard::{BoxedPointer, Doer, Holder};
+pub use crate::bag::Bag;
+pub use crate::counter::ShardedCounter;
+pub use crate::deque::Deque;
+pub use crate::hashmap::HashMap;
+pub use crate::hazard::{BoxedPointer, Doer, Holder, HolderArray};
+pub use crate::listset::ListSet;
+pub use crate::padded::CachePadded;
+pub use crate::pool::ObjectPool;
+pub use crate::priorityqueue::PriorityQueue;
 pub use crate::queue::Queue;
+pub use crate::segqueue::SegQueue;
+pub use crate::skipmap::SkipMap;
 pub use crate::stack::Stack;
+pub use crate::tagged::TaggedPtr;
@@ -0,0 +1,321 @@
+#![allow(unexpected_cfgs)]
+
+use crate::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use crate::{BoxedPointer, Doer, Holder, TaggedPtr};
+
+static DROPBOX: BoxedPointer = BoxedPointer::new();
+
+// The tag of a next pointer that marks the node owning the pointer as deleted.
+const MARKED: usize = 1;
+
+// A next pointer with a single bit for the mark.
+type Link<T> = TaggedPtr<Node<T>, 1>;
+
+struct Node<T> {
+    value: T,
+    next: Link<T>,
+}
+
+// The holders that keep the predecessor and the current node of a search protected.
+#[derive(Default)]
+struct Holders {
+    pred: Holder,
+    curr: Holder,
+}
+
+// Where a search ended. A null predecessor stands for the head.
+struct Position<T> {
+    pred: *mut Node<T>,
+    curr: *mut Node<T>,
+}
+
+/// A sorted set built on a Harris-Michael list. A node is removed by first marking its next
+/// pointer, after which nobody can link anything behind it, and then unlinking it, which any
+/// thread that comes across it might do. Unlinked nodes are retired through the hazard domain,
+/// which drops their values on whichever thread reclaims them, so values have to be Send and
+/// 'static.
+pub struct ListSet<T> {
+    head: Link<T>,
+    len: AtomicUsize,
+    marker: PhantomData<Node<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for ListSet<T> {}
+unsafe impl<T: Send + Sync> Sync for ListSet<T> {}
+
+impl<T> Drop for ListSet<T> {
+    fn drop(&mut self) {
+        // Nodes that are still linked have not been retired, even the marked ones.
+        let (mut current, _) = self.head.load(Ordering::Acquire);
+        while !current.is_null() {
+            let owned = unsafe { Box::from_raw(current) };
+            (current, _) = owned.next.load(Ordering::Acquire);
+        }
+    }
+}
+
+impl<T: Ord + Send + 'static> Default for ListSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord + Send + 'static> FromIterator<T> for ListSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let set = Self::new();
+        for value in iter {
+            set.insert(value);
+        }
+        set
+    }
+}
+
+impl<T: Ord + Send + 'static> ListSet<T> {
+    pub fn new() -> Self {
+        Self {
+            head: Link::null(),
+            len: AtomicUsize::new(0),
+            marker: PhantomData,
+        }
+    }
+
+    /// Inserts the value. Returns false and drops the value if it was already present.
+    pub fn insert(&self, value: T) -> bool {
+        let node = Box::into_raw(Box::new(Node {
+            value,
+            next: Link::null(),
+        }));
+        let value = unsafe { &(*node).value };
+        let mut holders = Holders::default();
+        loop {
+            let position = self.find(|v| v >= value, &mut holders);
+            if !position.curr.is_null() && unsafe { &(*position.curr).value } == value {
+                std::mem::drop(unsafe { Box::from_raw(node) });
+                return false;
+            }
+            unsafe { (*node).next.store(position.curr, 0, Ordering::Relaxed) };
+            // Counted before the node is published so that a remove can not take the count
+            // below zero.
+            self.len.fetch_add(1, Ordering::Relaxed);
+            if self
+                .next(position.pred)
+                .compare_exchange(
+                    (position.curr, 0),
+                    (node, 0),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return true;
+            }
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Removes the value. Returns whether it was present.
+    pub fn remove<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut holders = Holders::default();
+        loop {
+            let position = self.find(|v| v.borrow() >= value, &mut holders);
+            let curr = position.curr;
+            if curr.is_null() || unsafe { (*curr).value.borrow() } != value {
+                return false;
+            }
+            let (succ, tag) = unsafe { (*curr).next.load(Ordering::Acquire) };
+            if tag == MARKED {
+                continue;
+            }
+            if unsafe { &(*curr).next }
+                .compare_exchange(
+                    (succ, 0),
+                    (succ, MARKED),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+            {
+                continue;
+            }
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            if self
+                .next(position.pred)
+                .compare_exchange((curr, 0), (succ, 0), Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                Self::retire(curr);
+            } else {
+                // Whoever moved the predecessor on left the node for a search to unlink.
+                self.find(|v| v.borrow() >= value, &mut holders);
+            }
+            return true;
+        }
+    }
+
+    pub fn contains<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut holders = Holders::default();
+        let position = self.find(|v| v.borrow() >= value, &mut holders);
+        !position.curr.is_null() && unsafe { (*position.curr).value.borrow() } == value
+    }
+
+    /// Returns an iterator over the values in ascending order. Every value that it hands out
+    /// stays protected until its entry gets dropped, even if it is removed in the meantime.
+    ///
+    /// The iterator is weakly consistent. Values inserted or removed during the iteration might
+    /// or might not be seen, but no value is ever seen twice.
+    pub fn iter(&self) -> Iter<'_, T> {
+        let mut holders = Holders::default();
+        let position = self.find(|_| true, &mut holders);
+        Iter {
+            set: self,
+            next: position.curr,
+            holder: holders.curr,
+        }
+    }
+
+    /// Returns the number of values. It is only an approximation under concurrent use.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether there was no value left at the point where the first one was looked for.
+    /// A value that has been removed but not unlinked yet does not count.
+    pub fn is_empty(&self) -> bool {
+        let mut holders = Holders::default();
+        self.find(|_| true, &mut holders).curr.is_null()
+    }
+
+    fn next(&self, pred: *mut Node<T>) -> &Link<T> {
+        if pred.is_null() {
+            &self.head
+        } else {
+            unsafe { &(*pred).next }
+        }
+    }
+
+    // Searches for the first node whose value passes the check and unlinks the marked nodes on
+    // the way. The current node is protected together with a validation of the predecessor's
+    // next pointer. If that pointer turns out to be marked, the predecessor is being removed and
+    // its successor might already be gone, so the search starts over from the head as in
+    // Michael's version of the Harris list.
+    //
+    // The predecessor and the current node stay protected in the holders until the next search.
+    fn find(&self, stop: impl Fn(&T) -> bool, holders: &mut Holders) -> Position<T> {
+        'retry: loop {
+            let mut pred: *mut Node<T> = ptr::null_mut();
+            let (mut curr, mut tag) = holders.curr.protect_tagged(self.next(pred));
+            loop {
+                if tag == MARKED {
+                    continue 'retry;
+                }
+                if curr.is_null() {
+                    return Position { pred, curr };
+                }
+                let (succ, succ_tag) = unsafe { (*curr).next.load(Ordering::Acquire) };
+                if succ_tag == MARKED {
+                    if self
+                        .next(pred)
+                        .compare_exchange((curr, 0), (succ, 0), Ordering::AcqRel, Ordering::Relaxed)
+                        .is_err()
+                    {
+                        continue 'retry;
+                    }
+                    Self::retire(curr);
+                    (curr, tag) = holders.curr.protect_tagged(self.next(pred));
+                    continue;
+                }
+                if stop(unsafe { &(*curr).value }) {
+                    return Position { pred, curr };
+                }
+                pred = curr;
+                std::mem::swap(&mut holders.pred, &mut holders.curr);
+                (curr, tag) = holders.curr.protect_tagged(self.next(pred));
+            }
+        }
+    }
+
+    fn retire(node: *mut Node<T>) {
+        let mut holder = Holder::default();
+        let wrapper = unsafe { holder.get_wrapper(&AtomicPtr::new(node), &DROPBOX) };
+        if let Some(mut wrapper) = wrapper {
+            wrapper.retire();
+        }
+    }
+}
+
+/// A value of a ListSet that is protected from being reclaimed for as long as the entry lives.
+pub struct Entry<'a, T> {
+    // Only held on to for the protection, which ends when it gets dropped.
+    _holder: Holder,
+    node: *mut Node<T>,
+    marker: PhantomData<&'a T>,
+}
+
+impl<T> Deref for Entry<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &(*self.node).value }
+    }
+}
+
+impl<T> Entry<'_, T> {
+    /// Returns whether the value has been removed from the set since it was handed out.
+    pub fn is_removed(&self) -> bool {
+        unsafe { (*self.node).next.load(Ordering::Acquire) }.1 == MARKED
+    }
+}
+
+/// An iterator over the values of a ListSet, see ListSet::iter.
+pub struct Iter<'a, T> {
+    set: &'a ListSet<T>,
+    // The node that comes next, which is protected by the holder.
+    next: *mut Node<T>,
+    holder: Holder,
+}
+
+impl<'a, T: Ord + Send + 'static> Iterator for Iter<'a, T> {
+    type Item = Entry<'a, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let curr = self.next;
+            if curr.is_null() {
+                return None;
+            }
+            let mut holder = Holder::default();
+            let (succ, tag) = holder.protect_tagged(unsafe { &(*curr).next });
+            if tag == MARKED {
+                // The node has been removed, so its successor might already be gone as well. We
+                // skip it and carry on from the first node after its value instead.
+                let mut holders = Holders::default();
+                let value = unsafe { &(*curr).value };
+                let position = self.set.find(|v| v > value, &mut holders);
+                self.next = position.curr;
+                self.holder = holders.curr;
+                continue;
+            }
+            // The hazard of our holder moves into the entry along with the node that it protects,
+            // so the node never goes unprotected.
+            self.next = succ;
+            return Some(Entry {
+                _holder: std::mem::replace(&mut self.holder, holder),
+                node: curr,
+                marker: PhantomData,
+            });
+        }
+    }
+}
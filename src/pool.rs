@@ -0,0 +1,132 @@
+use crate::sync::atomic::AtomicUsize;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::Ordering;
+
+use crate::Stack;
+
+type Factory<T> = Box<dyn Fn() -> T + Send + Sync>;
+type Reset<T> = Box<dyn Fn(&mut T) + Send + Sync>;
+
+/// A pool of reusable objects, such as large buffers, kept on a lock-free Stack. get hands out
+/// an idle object or makes a new one with the factory, and the object goes back into the pool
+/// when its handle gets dropped.
+///
+/// At most max_idle objects are kept around, anything returned beyond that is dropped. Objects
+/// that fail to be pushed or popped because of heavy contention on the stack are dropped or made
+/// anew in the same way, so the pool never makes anyone wait.
+pub struct ObjectPool<T> {
+    idle: Stack<T>,
+    // The number of idle objects, which is reserved before an object is pushed so that the cap
+    // holds exactly.
+    count: AtomicUsize,
+    max_idle: usize,
+    factory: Factory<T>,
+    reset: Option<Reset<T>>,
+}
+
+impl<T> ObjectPool<T> {
+    /// Creates a pool without a limit on the number of idle objects.
+    pub fn new<F>(factory: F) -> Self
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        Self::with_max_idle(usize::MAX, factory)
+    }
+
+    /// Creates a pool that keeps at most max_idle objects around.
+    pub fn with_max_idle<F>(max_idle: usize, factory: F) -> Self
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        Self {
+            idle: Stack::new(),
+            count: AtomicUsize::new(0),
+            max_idle,
+            factory: Box::new(factory),
+            reset: None,
+        }
+    }
+
+    /// Sets a hook that gets run on every object that goes back into the pool, so that it is
+    /// clean by the time it is reused. Objects that are dropped instead are not reset.
+    pub fn with_reset<R>(mut self, reset: R) -> Self
+    where
+        R: Fn(&mut T) + Send + Sync + 'static,
+    {
+        self.reset = Some(Box::new(reset));
+        self
+    }
+
+    /// Takes an idle object out of the pool, or makes a new one if there is none.
+    pub fn get(&self) -> Pooled<'_, T> {
+        let value = match self.idle.delete() {
+            Ok(value) => {
+                self.count.fetch_sub(1, Ordering::Relaxed);
+                value
+            }
+            Err(_) => (self.factory)(),
+        };
+        Pooled {
+            pool: self,
+            value: Some(value),
+        }
+    }
+
+    /// Returns the number of idle objects in the pool.
+    pub fn idle(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn put(&self, mut value: T) {
+        let reserved = self
+            .count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < self.max_idle).then_some(count + 1)
+            });
+        if reserved.is_err() {
+            return;
+        }
+        if let Some(reset) = &self.reset {
+            reset(&mut value);
+        }
+        // The stack drops the value itself if it gives up on the push.
+        if self.idle.insert(value).is_err() {
+            self.count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An object taken out of an ObjectPool. It goes back into the pool when it gets dropped.
+pub struct Pooled<'a, T> {
+    pool: &'a ObjectPool<T>,
+    // Only ever None after the value has been detached.
+    value: Option<T>,
+}
+
+impl<T> Pooled<'_, T> {
+    /// Takes the object out of the pool for good.
+    pub fn detach(mut self) -> T {
+        self.value.take().expect("The value is only taken once")
+    }
+}
+
+impl<T> Deref for Pooled<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.value.as_ref().expect("The value is only taken once")
+    }
+}
+
+impl<T> DerefMut for Pooled<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value.as_mut().expect("The value is only taken once")
+    }
+}
+
+impl<T> Drop for Pooled<'_, T> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            self.pool.put(value);
+        }
+    }
+}
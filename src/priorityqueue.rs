@@ -0,0 +1,389 @@
+#![allow(unexpected_cfgs)]
+
+use crate::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::cell::Cell;
+use std::cmp::Ordering as Compare;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use crate::{BoxedPointer, Doer, Holder, HolderArray};
+
+static DROPBOX: BoxedPointer = BoxedPointer::new();
+
+#[cfg(not(loom))]
+pub(crate) const MAX_LEVEL: usize = 16;
+
+// Keeps the number of holders, and with that the number of interleavings, small under loom.
+#[cfg(loom)]
+pub(crate) const MAX_LEVEL: usize = 2;
+
+// The lowest bit of a next pointer marks the node that owns the pointer as deleted on that level.
+// Nodes are aligned, so the bit is never part of an address.
+const MARK: usize = 1;
+
+fn is_marked<T>(ptr: *mut T) -> bool {
+    ptr as usize & MARK == MARK
+}
+
+fn marked<T>(ptr: *mut T) -> *mut T {
+    ptr.map_addr(|addr| addr | MARK)
+}
+
+fn unmarked<T>(ptr: *mut T) -> *mut T {
+    ptr.map_addr(|addr| addr & !MARK)
+}
+
+thread_local! {
+    static SEED: Cell<u64> = Cell::new({
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        hasher.finish() | 1
+    });
+}
+
+// Draws the height of a new tower, where every level is half as likely as the one below it.
+pub(crate) fn random_level() -> usize {
+    SEED.with(|seed| {
+        let mut x = seed.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        seed.set(x);
+        ((x.trailing_ones() as usize) + 1).min(MAX_LEVEL)
+    })
+}
+
+struct Node<K, V> {
+    key: K,
+    // Makes every entry unique, so that entries with the same key come out in insertion order
+    // and a search can always find its way to one specific node.
+    seq: usize,
+    value: MaybeUninit<V>,
+    next: Box<[AtomicPtr<Node<K, V>>]>,
+    // One reference for the inserter and one for every level that the node is linked on. The
+    // node gets retired once the last of them is gone, so it is never retired while it can still
+    // be reached on any level.
+    refs: AtomicUsize,
+}
+
+impl<K: Ord, V> Node<K, V> {
+    fn compare(&self, key: &K, seq: usize) -> Compare {
+        self.key.cmp(key).then(self.seq.cmp(&seq))
+    }
+}
+
+// The holders that keep the predecessors and successors of a search protected.
+struct Holders {
+    preds: HolderArray<MAX_LEVEL>,
+    succs: HolderArray<MAX_LEVEL>,
+}
+
+impl Holders {
+    fn new() -> Self {
+        Self {
+            preds: HolderArray::new(),
+            succs: HolderArray::new(),
+        }
+    }
+}
+
+// Where a search ended on every level. A null predecessor stands for the head.
+struct Position<K, V> {
+    preds: [*mut Node<K, V>; MAX_LEVEL],
+    succs: [*mut Node<K, V>; MAX_LEVEL],
+}
+
+/// A concurrent priority queue built on a lock-free skiplist. Every level is a sorted list in
+/// which a node gets deleted by marking its next pointer before it is unlinked, as in a
+/// Harris-Michael list. pop_min marks the first node on the bottom level and then unlinks it from
+/// every level through a search. Nodes are reclaimed through the hazard domain, which drops the
+/// keys inside on whichever thread reclaims them, so keys have to be Send and 'static.
+///
+/// Entries with equal keys are popped in the order in which they were inserted.
+pub struct PriorityQueue<K, V> {
+    head: [AtomicPtr<Node<K, V>>; MAX_LEVEL],
+    seq: AtomicUsize,
+    len: AtomicUsize,
+    marker: PhantomData<Node<K, V>>,
+}
+
+unsafe impl<K: Send + Sync, V: Send> Send for PriorityQueue<K, V> {}
+unsafe impl<K: Send + Sync, V: Send> Sync for PriorityQueue<K, V> {}
+
+impl<K, V> Drop for PriorityQueue<K, V> {
+    fn drop(&mut self) {
+        // A node that got deleted while it was being linked on the upper levels might only be
+        // reachable from there, so every level is walked. Nodes that are not reachable anymore
+        // have already been retired.
+        let mut nodes = HashSet::new();
+        for level in 0..MAX_LEVEL {
+            let mut current = unmarked(self.head[level].load(Ordering::Acquire));
+            while !current.is_null() {
+                nodes.insert(current);
+                current = unmarked(unsafe { (*current).next[level].load(Ordering::Acquire) });
+            }
+        }
+        for node in nodes {
+            let mut owned = unsafe { Box::from_raw(node) };
+            // The value of a node that is marked on the bottom level has been popped already.
+            if !is_marked(owned.next[0].load(Ordering::Acquire)) {
+                unsafe { owned.value.assume_init_drop() };
+            }
+        }
+    }
+}
+
+impl<K: Ord + Send + 'static, V> Default for PriorityQueue<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Send + 'static, V> PriorityQueue<K, V> {
+    pub fn new() -> Self {
+        Self {
+            head: std::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+            seq: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            marker: PhantomData,
+        }
+    }
+
+    /// Inserts the value with the given priority, where lower keys come out first.
+    pub fn insert(&self, key: K, value: V) {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let height = random_level();
+        let node = Box::into_raw(Box::new(Node {
+            key,
+            seq,
+            value: MaybeUninit::new(value),
+            next: (0..height)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            refs: AtomicUsize::new(1),
+        }));
+        // The reference of the inserter keeps the node alive until we are done with it.
+        let key = unsafe { &(*node).key };
+        let mut holders = Holders::new();
+        let mut position = self.find(key, seq, &mut holders);
+        // Counted before the node is published so that a pop can not take the count below zero.
+        self.len.fetch_add(1, Ordering::Relaxed);
+        loop {
+            unsafe { (*node).next[0].store(position.succs[0], Ordering::Relaxed) };
+            unsafe { (*node).refs.fetch_add(1, Ordering::Relaxed) };
+            if self
+                .next(position.preds[0], 0)
+                .compare_exchange(position.succs[0], node, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+            unsafe { (*node).refs.fetch_sub(1, Ordering::Relaxed) };
+            position = self.find(key, seq, &mut holders);
+        }
+        'levels: for level in 1..height {
+            loop {
+                let succ = position.succs[level];
+                // The next pointer is set with a compare_exchange so that we notice the node
+                // getting deleted and stop linking it any further.
+                let current = unsafe { (*node).next[level].load(Ordering::Acquire) };
+                if is_marked(current) {
+                    break 'levels;
+                }
+                if current != succ
+                    && unsafe { &(*node).next[level] }
+                        .compare_exchange(current, succ, Ordering::AcqRel, Ordering::Relaxed)
+                        .is_err()
+                {
+                    break 'levels;
+                }
+                unsafe { (*node).refs.fetch_add(1, Ordering::Relaxed) };
+                if self
+                    .next(position.preds[level], level)
+                    .compare_exchange(succ, node, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break;
+                }
+                unsafe { (*node).refs.fetch_sub(1, Ordering::Relaxed) };
+                position = self.find(key, seq, &mut holders);
+            }
+        }
+        // A node that was deleted while we were linking it might have been linked on a level
+        // after the deleter unlinked it there, so we unlink it ourselves.
+        if is_marked(unsafe { (*node).next[0].load(Ordering::Acquire) }) {
+            self.find(key, seq, &mut holders);
+        }
+        std::mem::drop(holders);
+        self.release(node);
+    }
+
+    /// Removes the entry with the lowest key, or the earliest inserted one among several with
+    /// the lowest key.
+    ///
+    /// Searches that reached the node before it was unlinked may still compare against its key,
+    /// so the key is cloned instead of moved out and the node keeps its own copy until it gets
+    /// reclaimed. That way a pop never has to wait for other threads.
+    pub fn pop_min(&self) -> Option<(K, V)>
+    where
+        K: Clone,
+    {
+        let mut holders = Holders::new();
+        loop {
+            let mut holder = Holder::default();
+            let guard = unsafe { holder.load_pointer(&self.head[0]) }?;
+            // Marking the upper levels first stops an inserter that is still linking the node.
+            for level in (1..guard.next.len()).rev() {
+                let next = &guard.next[level];
+                let mut current = next.load(Ordering::Acquire);
+                while !is_marked(current) {
+                    match next.compare_exchange(
+                        current,
+                        marked(current),
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    ) {
+                        Ok(_) => break,
+                        Err(actual) => current = actual,
+                    }
+                }
+            }
+            // Whoever marks the bottom level owns the entry.
+            let mut current = guard.next[0].load(Ordering::Acquire);
+            let won = loop {
+                if is_marked(current) {
+                    break false;
+                }
+                match guard.next[0].compare_exchange(
+                    current,
+                    marked(current),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => break true,
+                    Err(actual) => current = actual,
+                }
+            };
+            let key = &guard.key;
+            let seq = guard.seq;
+            if !won {
+                // Help the winner unlink the node so that the head moves on.
+                self.find(key, seq, &mut holders);
+                continue;
+            }
+            let value = unsafe { guard.value.assume_init_read() };
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            self.find(key, seq, &mut holders);
+            return Some((key.clone(), value));
+        }
+    }
+
+    /// Returns the number of entries. It is only an approximation under concurrent use.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether there was no entry left at the point where the bottom level was read.
+    /// An entry that has been popped but not unlinked yet does not count.
+    pub fn is_empty(&self) -> bool {
+        let mut holders = Holders::new();
+        loop {
+            let mut holder = Holder::default();
+            let guard = match unsafe { holder.load_pointer(&self.head[0]) } {
+                Some(guard) => guard,
+                None => return true,
+            };
+            if !is_marked(guard.next[0].load(Ordering::Acquire)) {
+                return false;
+            }
+            // The first entry has been popped but it has not been unlinked yet.
+            self.find(&guard.key, guard.seq, &mut holders);
+        }
+    }
+
+    fn next(&self, pred: *mut Node<K, V>, level: usize) -> &AtomicPtr<Node<K, V>> {
+        if pred.is_null() {
+            &self.head[level]
+        } else {
+            unsafe { &(*pred).next[level] }
+        }
+    }
+
+    // Searches for the position of the entry on every level and unlinks the marked nodes on the
+    // way. A node is only ever dereferenced after it has been protected and found to be linked
+    // to an unmarked predecessor, which means that it still holds the reference of that level
+    // and can not have been retired. Whenever that can not be established the search starts
+    // over from the head, as in Michael's version of the Harris list.
+    //
+    // The predecessors and successors stay protected in the holders until the next search.
+    fn find(&self, key: &K, seq: usize, holders: &mut Holders) -> Position<K, V> {
+        let mut position = Position {
+            preds: [ptr::null_mut(); MAX_LEVEL],
+            succs: [ptr::null_mut(); MAX_LEVEL],
+        };
+        'retry: loop {
+            let mut pred: *mut Node<K, V> = ptr::null_mut();
+            for level in (0..MAX_LEVEL).rev() {
+                // The predecessor is still protected by the holder of the level above.
+                holders.preds[level].protect(pred);
+                let mut curr = self.next(pred, level).load(Ordering::Acquire);
+                loop {
+                    if is_marked(curr) {
+                        // The predecessor is being deleted on this level.
+                        continue 'retry;
+                    }
+                    if curr.is_null() {
+                        break;
+                    }
+                    holders.succs[level].protect(curr);
+                    if self.next(pred, level).load(Ordering::Acquire) != curr {
+                        continue 'retry;
+                    }
+                    let succ = unsafe { (*curr).next[level].load(Ordering::Acquire) };
+                    if is_marked(succ) {
+                        if self
+                            .next(pred, level)
+                            .compare_exchange(
+                                curr,
+                                unmarked(succ),
+                                Ordering::AcqRel,
+                                Ordering::Relaxed,
+                            )
+                            .is_err()
+                        {
+                            continue 'retry;
+                        }
+                        self.release(curr);
+                        curr = unmarked(succ);
+                        continue;
+                    }
+                    if unsafe { (*curr).compare(key, seq) } != Compare::Less {
+                        break;
+                    }
+                    pred = curr;
+                    let (preds, succs) = (&mut holders.preds, &mut holders.succs);
+                    std::mem::swap(&mut preds[level], &mut succs[level]);
+                    curr = succ;
+                }
+                position.preds[level] = pred;
+                position.succs[level] = curr;
+            }
+            return position;
+        }
+    }
+
+    // Drops one reference of the node and retires it once there are none left.
+    fn release(&self, node: *mut Node<K, V>) {
+        if unsafe { (*node).refs.fetch_sub(1, Ordering::AcqRel) } == 1 {
+            let mut holder = Holder::default();
+            let wrapper = unsafe { holder.get_wrapper(&AtomicPtr::new(node), &DROPBOX) };
+            if let Some(mut wrapper) = wrapper {
+                wrapper.retire();
+            }
+        }
+    }
+}
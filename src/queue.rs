@@ -1,23 +1,84 @@
-use crate::sync::atomic::AtomicPtr;
+use crate::claim::Claim;
+use crate::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::future::Future;
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
+use std::pin::Pin;
 use std::ptr;
 use std::sync::atomic::Ordering;
 
-use crate::{BoxedPointer, Doer, Holder};
+use crate::hazard::{Deleter, Uniform};
+use crate::wait::{WaitList, Waiter};
+use crate::{BoxedPointer, CachePadded, Doer, Holder};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 static DROPBOX: BoxedPointer = BoxedPointer::new();
+static RECYCLER: Recycler = Recycler;
 
+// Stored in the next pointer of the last node once the queue is closed, so that linking a node
+// after it fails. Nodes are aligned, so no node can ever be at this address.
+fn closed<T>() -> *mut Node<T> {
+    ptr::without_provenance_mut(1)
+}
+
+// Whether the next pointer ends the queue, either because nothing has been linked yet or because
+// the queue has been closed.
+fn is_end<T>(next: *mut Node<T>) -> bool {
+    next.is_null() || next == closed()
+}
+
+/// The error returned by an enqueue on a closed queue. It carries back what was to be enqueued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed<T>(pub T);
+
+/// The error returned by a dequeue that found no value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DequeueError {
+    /// The queue was empty but values might still be enqueued later.
+    Empty,
+    /// The queue was empty and closed, so no value will ever be enqueued again.
+    ClosedAndEmpty,
+}
+
+impl<T> std::fmt::Display for Closed<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The queue has been closed")
+    }
+}
+
+impl std::fmt::Display for DequeueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DequeueError::Empty => f.write_str("There are no elements in the queue"),
+            DequeueError::ClosedAndEmpty => {
+                f.write_str("The queue has been closed and there are no elements left")
+            }
+        }
+    }
+}
+
+impl<T: std::fmt::Debug> std::error::Error for Closed<T> {}
+impl std::error::Error for DequeueError {}
+
+#[repr(C)]
 struct Node<T> {
+    // This has to stay the first field as the recycling deleter reads it without knowing T. It is
+    // only set right before a node of a pooled queue gets retired.
+    pool: *const PoolHeader,
     value: MaybeUninit<T>,
     next: AtomicPtr<Node<T>>,
+    claim: Claim,
 }
 
 impl<T> Node<T> {
     fn new() -> Self {
         Self {
+            pool: ptr::null(),
             value: MaybeUninit::uninit(),
             next: AtomicPtr::new(ptr::null_mut()),
+            claim: Claim::new(),
         }
     }
 
@@ -26,9 +87,21 @@ impl<T> Node<T> {
     }
 }
 
+/// A lock-free FIFO queue in the style of Michael and Scott. Nodes are reclaimed through the
+/// hazard domain.
+///
+/// Reading a value in place, through peek_with or the Debug and Clone implementations, is the
+/// one exception to the queue being lock-free. A dequeue, dequeue_batch or dequeue_into that
+/// takes a value while such a read of that same value runs waits for the read to finish before
+/// moving the value out. Enqueues and dequeues of other values are never held up.
 pub struct Queue<T> {
-    head: AtomicPtr<Node<T>>,
-    tail: AtomicPtr<Node<T>>,
+    // The head is moved by dequeuers and the tail by enqueuers, so they live on their own cache
+    // lines to keep the two sides from slowing each other down.
+    head: CachePadded<AtomicPtr<Node<T>>>,
+    tail: CachePadded<AtomicPtr<Node<T>>>,
+    len: AtomicUsize,
+    pool: Option<Arc<NodePool<T>>>,
+    waiters: WaitList,
     marker: PhantomData<Node<T>>,
 }
 
@@ -37,11 +110,18 @@ unsafe impl<T> Sync for Queue<T> where T: Send {}
 
 impl<T> Drop for Queue<T> {
     fn drop(&mut self) {
+        // The value of the sentinel has either never been written or has already been moved
+        // out, every node after it still owns its value.
         let mut current = self.head.load(Ordering::Acquire);
-        while !current.is_null() {
+        let mut sentinel = true;
+        while !is_end(current) {
             let new = unsafe { (*current).next.load(Ordering::Acquire) };
-            let owned = unsafe { Box::from_raw(current) };
+            let mut owned = unsafe { Box::from_raw(current) };
+            if !sentinel {
+                unsafe { owned.value.assume_init_drop() };
+            }
             std::mem::drop(owned);
+            sentinel = false;
             current = new;
         }
     }
@@ -53,20 +133,289 @@ impl<T> Default for Queue<T> {
     }
 }
 
+impl<T> FromIterator<T> for Queue<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let queue = Queue::new();
+        let _ = queue.enqueue_batch(iter);
+        queue
+    }
+}
+
+/// Values that are added to a closed queue are dropped.
+impl<T> Extend<T> for Queue<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let _ = self.enqueue_batch(iter);
+    }
+}
+
+/// An owning iterator over the values of a queue in FIFO order.
+pub struct IntoIter<T> {
+    queue: Queue<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    // The queue is owned by the iterator so there are no other threads around and the nodes can
+    // be unlinked and freed directly instead of going through the hazard domain.
+    fn next(&mut self) -> Option<T> {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        let next = unsafe { (*head).next.load(Ordering::Acquire) };
+        if is_end(next) {
+            return None;
+        }
+        self.queue.head.store(next, Ordering::Relaxed);
+        if self.queue.tail.load(Ordering::Relaxed) == head {
+            self.queue.tail.store(next, Ordering::Relaxed);
+        }
+        self.queue.len.fetch_sub(1, Ordering::Relaxed);
+        let value = unsafe { (*next).value.assume_init_read() };
+        std::mem::drop(unsafe { Box::from_raw(head) });
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.queue.len();
+        (len, Some(len))
+    }
+}
+
+impl<T> IntoIterator for Queue<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { queue: self }
+    }
+}
+
+/// A future that resolves to the next value of the queue. It is returned by [`Queue::recv`].
+pub struct Recv<'a, T> {
+    registration: Registration<'a, T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().registration.poll(cx)
+    }
+}
+
+/// A stream of the values of the queue. It is returned by [`Queue::stream`] and keeps its
+/// registration in the wait list between polls.
+pub struct RecvStream<'a, T> {
+    registration: Registration<'a, T>,
+}
+
+impl<T> RecvStream<'_, T> {
+    /// Polls for the next value in the way Stream::poll_next does. The stream ends once the
+    /// queue has been closed and drained.
+    pub fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().registration.poll(cx)
+    }
+}
+
+/// The waiter of a task that is waiting for a value. Only the latest waker of the task is kept
+/// registered, an older waiter gets cancelled when the task is polled with a different one.
+pub(crate) struct Registration<'a, T> {
+    queue: &'a Queue<T>,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<T> Drop for Registration<'_, T> {
+    fn drop(&mut self) {
+        // The task might have been notified but it is not going to look for the value anymore.
+        if let Some(waiter) = self.waiter.take() {
+            self.release(waiter);
+        }
+    }
+}
+
+impl<'a, T> Registration<'a, T> {
+    pub(crate) fn new(queue: &'a Queue<T>) -> Self {
+        Self {
+            queue,
+            waiter: None,
+        }
+    }
+
+    /// Polls for a value. Resolves to None once the queue has been closed and drained.
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        match self.queue.dequeue() {
+            Ok(value) => {
+                if let Some(waiter) = self.waiter.take() {
+                    self.release(waiter);
+                }
+                return Poll::Ready(Some(value));
+            }
+            Err(DequeueError::ClosedAndEmpty) => {
+                if let Some(waiter) = self.waiter.take() {
+                    self.release(waiter);
+                }
+                return Poll::Ready(None);
+            }
+            Err(DequeueError::Empty) => {}
+        }
+        if let Some(waiter) = &self.waiter
+            && waiter.will_wake(cx.waker())
+        {
+            return Poll::Pending;
+        }
+        // Either the task has been notified, and the queue has just been checked for the value,
+        // or it is polled with another waker. The old waiter is of no use in both cases.
+        if let Some(waiter) = self.waiter.take() {
+            self.queue.waiters.cancel(&waiter);
+        }
+        let waiter = Arc::new(Waiter::with_waker(cx.waker().clone()));
+        match self.queue.register(&waiter) {
+            Ok(value) => Poll::Ready(Some(value)),
+            Err(DequeueError::ClosedAndEmpty) => Poll::Ready(None),
+            Err(DequeueError::Empty) => {
+                self.waiter = Some(waiter);
+                Poll::Pending
+            }
+        }
+    }
+
+    // Cancels the waiter of a task that is done waiting and passes the notification on if the
+    // waiter had already been notified.
+    fn release(&self, waiter: Arc<Waiter>) {
+        if !self.queue.waiters.cancel(&waiter) {
+            self.queue.waiters.notify_one();
+        }
+    }
+}
+
+impl<T: std::fmt::Debug + Sync> std::fmt::Debug for Queue<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let alternate = f.alternate();
+        let values = self.snapshot(|value| {
+            if alternate {
+                format!("{:#?}", value)
+            } else {
+                format!("{:?}", value)
+            }
+        });
+        f.debug_list()
+            .entries(values.iter().map(|value| Formatted(value)))
+            .finish()
+    }
+}
+
+impl<T: Clone + Sync> Clone for Queue<T> {
+    fn clone(&self) -> Self {
+        self.snapshot(T::clone).into_iter().collect()
+    }
+}
+
+// Writes an already formatted value as it is, so that the entries of a snapshot do not get
+// quoted like strings would.
+pub(crate) struct Formatted<'a>(pub(crate) &'a str);
+
+impl std::fmt::Debug for Formatted<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
 impl<T> Queue<T> {
     pub fn new() -> Self {
         let sentinel_node = Box::into_raw(Box::new(Node::new()));
         Self {
-            head: AtomicPtr::new(sentinel_node),
-            tail: AtomicPtr::new(sentinel_node),
+            head: CachePadded::new(AtomicPtr::new(sentinel_node)),
+            tail: CachePadded::new(AtomicPtr::new(sentinel_node)),
+            len: AtomicUsize::new(0),
+            pool: None,
+            waiters: WaitList::new(),
             marker: PhantomData,
         }
     }
 
-    pub fn enqueue(&self, value: T) {
+    /// Creates a queue that keeps the nodes of dequeued values in a free list and reuses them for
+    /// later enqueues instead of going through the allocator each time. At most capacity nodes
+    /// are kept idle, the rest are freed as usual.
+    pub fn with_node_pool(capacity: usize) -> Self {
+        let mut queue = Self::new();
+        queue.pool = Some(Arc::new(NodePool::new(capacity)));
+        queue
+    }
+
+    /// Returns the statistics of the node pool or None if the queue was created without one.
+    pub fn node_pool_stats(&self) -> Option<NodePoolStats> {
+        self.pool.as_ref().map(|pool| pool.stats())
+    }
+
+    /// Enqueues the value. Fails once the queue has been closed, handing the value back.
+    pub fn enqueue(&self, value: T) -> Result<(), Closed<T>> {
+        let allocated = self.allocate(value);
+        self.len.fetch_add(1, Ordering::Relaxed);
+        if !self.link(allocated, allocated) {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            return Err(Closed(self.release_chain(allocated, 1).remove(0)));
+        }
+        self.waiters.notify_one();
+        Ok(())
+    }
+
+    /// Enqueues all the values of the iterator. The nodes are first linked together privately
+    /// and the whole chain is then published with a single compare_exchange on the next pointer
+    /// of the tail, so the values of one batch always end up next to each other in the queue.
+    /// Either all of the values get enqueued or, if the queue has been closed, none of them.
+    pub fn enqueue_batch<I>(&self, values: I) -> Result<(), Closed<Vec<T>>>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut values = values.into_iter();
+        let first = if let Some(value) = values.next() {
+            self.allocate(value)
+        } else {
+            return Ok(());
+        };
+        let mut last = first;
+        let mut count = 1;
+        for value in values {
+            let allocated = self.allocate(value);
+            // The chain is not visible to any other thread until it gets linked to the tail and
+            // that compare_exchange publishes all of these stores.
+            unsafe { (*last).next.store(allocated, Ordering::Relaxed) };
+            last = allocated;
+            count += 1;
+        }
+        self.len.fetch_add(count, Ordering::Relaxed);
+        if !self.link(first, last) {
+            self.len.fetch_sub(count, Ordering::Relaxed);
+            return Err(Closed(self.release_chain(first, count)));
+        }
+        self.waiters.notify(count);
+        Ok(())
+    }
+
+    // Takes the values back out of a chain that could not be linked and frees its nodes. The
+    // chain has never been visible to other threads.
+    fn release_chain(&self, first: *mut Node<T>, count: usize) -> Vec<T> {
+        let mut values = Vec::with_capacity(count);
+        let mut node = first;
+        for _ in 0..count {
+            let owned = unsafe { Box::from_raw(node) };
+            values.push(unsafe { owned.value.assume_init_read() });
+            node = owned.next.load(Ordering::Relaxed);
+        }
+        values
+    }
+
+    fn allocate(&self, value: T) -> *mut Node<T> {
+        if let Some(node) = self.pool.as_ref().and_then(|pool| pool.take()) {
+            unsafe { (*node).write(value) };
+            return node;
+        }
         let mut node = Node::new();
         node.write(value);
-        let allocated = Box::into_raw(Box::new(node));
+        Box::into_raw(Box::new(node))
+    }
+
+    // Links the chain after the last node. Returns false if the queue has been closed.
+    fn link(&self, first: *mut Node<T>, last: *mut Node<T>) -> bool {
         loop {
             let mut holder = Holder::default();
             let guard = unsafe {
@@ -77,24 +426,40 @@ impl<T> Queue<T> {
             let cas_result = unsafe {
                 (*guard.data).next.compare_exchange(
                     ptr::null_mut(),
-                    allocated,
+                    first,
                     Ordering::AcqRel,
-                    Ordering::Relaxed,
+                    Ordering::Acquire,
                 )
             };
-            if cas_result.is_ok() {
-                let _ = self.tail.compare_exchange(
-                    guard.data,
-                    allocated,
-                    Ordering::AcqRel,
-                    Ordering::Relaxed,
-                );
-                return;
+            match cas_result {
+                Ok(_) => {
+                    let _ = self.tail.compare_exchange(
+                        guard.data,
+                        last,
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    );
+                    return true;
+                }
+                Err(next) if next == closed() => return false,
+                Err(next) => {
+                    // Some other thread has linked its nodes but has not swung the tail yet. We
+                    // help it along instead of waiting as the tail of a linked batch may only be
+                    // reached one node at a time.
+                    let _ = self.tail.compare_exchange(
+                        guard.data,
+                        next,
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    );
+                }
             }
         }
     }
 
-    pub fn dequeue(&self) -> Result<T, &str> {
+    /// Dequeues the value at the front. An empty queue is reported as ClosedAndEmpty once it has
+    /// been closed, which is final, and as Empty otherwise.
+    pub fn dequeue(&self) -> Result<T, DequeueError> {
         loop {
             let mut current_head_holder = Holder::default();
             let mut next_node_holder = Holder::default();
@@ -103,12 +468,17 @@ impl<T> Queue<T> {
                     .load_pointer(&self.head)
                     .expect("Sentiled node will never allow it to be null")
             };
+            // The closed marker has to be checked for up front, as load_pointer masks it off
+            // like any other tag and hands out None for it.
+            if unsafe { (*current_head_guard.data).next.load(Ordering::Acquire) } == closed() {
+                return Err(DequeueError::ClosedAndEmpty);
+            }
             let next_node_guard = if let Some(guard) =
                 unsafe { next_node_holder.load_pointer(&(*current_head_guard.data).next) }
             {
                 guard
             } else {
-                return Err("There are no elements in the queue");
+                return Err(DequeueError::Empty);
             };
             let mut tail_holder = Holder::default();
             let tail_guard = unsafe {
@@ -124,26 +494,493 @@ impl<T> Queue<T> {
                     Ordering::Relaxed,
                 );
             }
+            // SeqCst pairs with the readers of the value, see Claim.
             if self
                 .head
                 .compare_exchange(
                     current_head_guard.data,
                     next_node_guard.data,
-                    Ordering::AcqRel,
+                    Ordering::SeqCst,
                     Ordering::Relaxed,
                 )
                 .is_ok()
             {
+                unsafe { (*next_node_guard.data).claim.take() };
                 let read_value = unsafe { (*next_node_guard.data).value.assume_init_read() };
-                let mut swap_holder = Holder::default();
-                let wrapper = unsafe {
-                    swap_holder.get_wrapper(&AtomicPtr::new(current_head_guard.data), &DROPBOX)
-                };
-                if let Some(mut wrapper) = wrapper {
-                    wrapper.retire();
-                }
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                self.retire(current_head_guard.data);
                 return Ok(read_value);
             }
         }
     }
+
+    /// Dequeues a value, parking the current thread until one gets enqueued if the queue is empty.
+    /// Fails once the queue has been closed and drained.
+    pub fn dequeue_blocking(&self) -> Result<T, DequeueError> {
+        self.wait_for(None)
+    }
+
+    /// Dequeues a value, parking the current thread for at most the given duration if the queue
+    /// is empty. Running out of time is reported as Empty.
+    pub fn dequeue_timeout(&self, timeout: Duration) -> Result<T, DequeueError> {
+        self.wait_for(Some(Instant::now() + timeout))
+    }
+
+    // Dequeues a value, parking the current thread while the queue is empty. Only returns Empty
+    // once the deadline has passed.
+    fn wait_for(&self, deadline: Option<Instant>) -> Result<T, DequeueError> {
+        let expired = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        loop {
+            match self.dequeue() {
+                Err(DequeueError::Empty) if !expired() => {}
+                result => return result,
+            }
+            let waiter = Arc::new(Waiter::new(std::thread::current()));
+            match self.register(&waiter) {
+                Err(DequeueError::Empty) => {}
+                result => return result,
+            }
+            while !waiter.is_notified() {
+                if expired() {
+                    // If the cancellation fails we have been notified in the meantime and the
+                    // next round picks up the value.
+                    self.waiters.cancel(&waiter);
+                    break;
+                }
+                match deadline {
+                    Some(deadline) => std::thread::park_timeout(
+                        deadline.saturating_duration_since(Instant::now()),
+                    ),
+                    None => std::thread::park(),
+                }
+            }
+        }
+    }
+
+    /// Closes the queue. Every enqueue that comes after it fails, while the values that are
+    /// already in the queue can still be dequeued. Returns false if the queue had already been
+    /// closed. Threads and tasks that wait for a value get woken up once it is drained.
+    ///
+    /// The queue is closed by linking a marker after the last node, so closing is ordered with
+    /// the enqueues on the same compare_exchange that they use to link their nodes.
+    pub fn close(&self) -> bool {
+        loop {
+            let mut holder = Holder::default();
+            let guard = unsafe {
+                holder
+                    .load_pointer(&self.tail)
+                    .expect("Sentinel node guarantees that the tail pointer is never null")
+            };
+            let cas_result = unsafe {
+                (*guard.data).next.compare_exchange(
+                    ptr::null_mut(),
+                    closed(),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+            };
+            match cas_result {
+                Ok(_) => {
+                    self.waiters.notify(usize::MAX);
+                    return true;
+                }
+                Err(next) if next == closed() => return false,
+                Err(next) => {
+                    let _ = self.tail.compare_exchange(
+                        guard.data,
+                        next,
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns whether the queue has been closed.
+    pub fn is_closed(&self) -> bool {
+        loop {
+            let mut holder = Holder::default();
+            let guard = unsafe {
+                holder
+                    .load_pointer(&self.tail)
+                    .expect("Sentinel node guarantees that the tail pointer is never null")
+            };
+            let next = unsafe { (*guard.data).next.load(Ordering::Acquire) };
+            if is_end(next) {
+                return next == closed();
+            }
+            let _ =
+                self.tail
+                    .compare_exchange(guard.data, next, Ordering::AcqRel, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns a future that resolves to the next value of the queue. While the queue is empty
+    /// the waker of the task is registered in the same wait list as blocked threads, so any
+    /// executor gets its task woken up by an enqueue.
+    pub fn recv(&self) -> Recv<'_, T> {
+        Recv {
+            registration: Registration::new(self),
+        }
+    }
+
+    /// Returns a stream of the values of the queue.
+    pub fn stream(&self) -> RecvStream<'_, T> {
+        RecvStream {
+            registration: Registration::new(self),
+        }
+    }
+
+    // Registers the waiter and checks the queue once more, as a value might have been enqueued
+    // before the registration became visible to the enqueuer.
+    fn register(&self, waiter: &Arc<Waiter>) -> Result<T, DequeueError> {
+        self.waiters.register(Arc::clone(waiter));
+        let result = self.dequeue();
+        if result.is_ok() && !self.waiters.cancel(waiter) {
+            // We were notified as well and since we are not going to wait for a value anymore,
+            // the notification is passed on to somebody who is.
+            self.waiters.notify_one();
+        }
+        if let Err(DequeueError::ClosedAndEmpty) = result {
+            // Closing notifies everyone, there is nothing to pass on.
+            self.waiters.cancel(waiter);
+        }
+        result
+    }
+
+    /// Returns the number of values in the queue. The counter is updated with relaxed ordering
+    /// around the operations, so under concurrent use it is only an approximation.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the queue had no values at the point where the next pointer of the
+    /// sentinel node was read.
+    pub fn is_empty(&self) -> bool {
+        let mut holder = Holder::default();
+        let guard = unsafe {
+            holder
+                .load_pointer(&self.head)
+                .expect("Sentinel node will never allow it to be null")
+        };
+        unsafe { is_end((*guard.data).next.load(Ordering::Acquire)) }
+    }
+
+    /// Calls f with a reference to the value at the front of the queue without removing it.
+    /// A dequeue of that value waits for f to return before moving it out, so f should be short.
+    /// In particular f must not dequeue from the same queue, which would wait for f itself and
+    /// never return. Dequeues of other values are not held up.
+    pub fn peek_with<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&T) -> R,
+        T: Sync,
+    {
+        loop {
+            let mut head_holder = Holder::default();
+            let mut next_holder = Holder::default();
+            let head_guard = unsafe {
+                head_holder
+                    .load_pointer(&self.head)
+                    .expect("Sentinel node will never allow it to be null")
+            };
+            // A closed queue ends in a marker that load_pointer masks off to None.
+            let next_guard = unsafe { next_holder.load_pointer(&(*head_guard.data).next) }?;
+            // The head might have moved past the node in between the loads in which case the node
+            // might have been retired before we protected it.
+            if self.head.load(Ordering::Acquire) != head_guard.data {
+                continue;
+            }
+            let linked = || self.head.load(Ordering::SeqCst) == head_guard.data;
+            if let Some(_reader) = next_guard.claim.enter(linked) {
+                return Some(f(unsafe { next_guard.value.assume_init_ref() }));
+            }
+        }
+    }
+
+    // Maps every value from the front to the back. A node that is reachable from the head can not
+    // have been retired, so the walk validates the head after protecting each node and starts
+    // over once it moves. It therefore only finishes once it gets through the whole queue
+    // without a concurrent dequeue, which is meant for a queue that is quiescent.
+    fn snapshot<F, R>(&self, mut f: F) -> Vec<R>
+    where
+        F: FnMut(&T) -> R,
+    {
+        let mut values = Vec::new();
+        'walk: loop {
+            values.clear();
+            let mut head_holder = Holder::default();
+            let head_guard = unsafe {
+                head_holder
+                    .load_pointer(&self.head)
+                    .expect("Sentinel node will never allow it to be null")
+            };
+            let head = head_guard.data;
+            let mut holder = Holder::default();
+            let mut current = head;
+            loop {
+                let next = unsafe { (*current).next.load(Ordering::Acquire) };
+                if is_end(next) {
+                    return values;
+                }
+                holder.protect(next);
+                if self.head.load(Ordering::Acquire) != head {
+                    continue 'walk;
+                }
+                let linked = || self.head.load(Ordering::SeqCst) == head;
+                if let Some(_reader) = unsafe { (*next).claim.enter(linked) } {
+                    values.push(f(unsafe { (*next).value.assume_init_ref() }));
+                } else {
+                    continue 'walk;
+                }
+                current = next;
+            }
+        }
+    }
+
+    /// Dequeues at most max values, returning them in FIFO order. The values are detached from the
+    /// queue with a single compare_exchange on the head, so an empty vector means that the queue
+    /// was empty.
+    pub fn dequeue_batch(&self, max: usize) -> Vec<T> {
+        let mut values = Vec::new();
+        self.detach(max, &mut values);
+        values
+    }
+
+    /// Moves every value that is in the queue at the time of the call into the vector and
+    /// returns how many of them were moved.
+    pub fn dequeue_into(&self, values: &mut Vec<T>) -> usize {
+        self.detach(usize::MAX, values)
+    }
+
+    fn detach(&self, max: usize, values: &mut Vec<T>) -> usize {
+        if max == 0 {
+            return 0;
+        }
+        loop {
+            let mut head_holder = Holder::default();
+            let head_guard = unsafe {
+                head_holder
+                    .load_pointer(&self.head)
+                    .expect("Sentinel node will never allow it to be null")
+            };
+            let head = head_guard.data;
+            let tail = self.tail.load(Ordering::Acquire);
+            if head == tail {
+                let next = unsafe { (*head).next.load(Ordering::Acquire) };
+                if is_end(next) {
+                    return 0;
+                }
+                let _ = self
+                    .tail
+                    .compare_exchange(tail, next, Ordering::AcqRel, Ordering::Relaxed);
+                continue;
+            }
+            // Walk hand over hand up to the tail that we have seen. A node that is still reachable
+            // from the head we have protected can not have been retired yet, so validating the
+            // head after protecting the next node is enough to make dereferencing it safe.
+            let mut current = Holder::default();
+            let mut previous = Holder::default();
+            let mut last = head;
+            let mut count = 0;
+            while count < max && last != tail {
+                let next = unsafe { (*last).next.load(Ordering::Acquire) };
+                if is_end(next) {
+                    break;
+                }
+                current.protect(next);
+                if self.head.load(Ordering::Acquire) != head {
+                    break;
+                }
+                std::mem::swap(&mut current, &mut previous);
+                last = next;
+                count += 1;
+            }
+            if count == 0 {
+                continue;
+            }
+            // SeqCst pairs with the readers of the values, see Claim.
+            if self
+                .head
+                .compare_exchange(head, last, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+            {
+                values.reserve(count);
+                let mut node = head;
+                for _ in 0..count {
+                    let next = unsafe { (*node).next.load(Ordering::Acquire) };
+                    unsafe { (*next).claim.take() };
+                    values.push(unsafe { (*next).value.assume_init_read() });
+                    self.retire(node);
+                    node = next;
+                }
+                self.len.fetch_sub(count, Ordering::Relaxed);
+                return count;
+            }
+        }
+    }
+
+    fn retire(&self, node: *mut Node<T>) {
+        let deleter: &'static dyn Deleter = if let Some(pool) = &self.pool {
+            // Every retired node holds on to the pool, so it stays around even if the queue gets
+            // dropped before the node is reclaimed.
+            let pool = Arc::into_raw(Arc::clone(pool));
+            unsafe { (*node).pool = pool as *const PoolHeader };
+            &RECYCLER
+        } else {
+            &DROPBOX
+        };
+        let mut holder = Holder::default();
+        let wrapper = unsafe { holder.get_wrapper(&AtomicPtr::new(node), deleter) };
+        if let Some(mut wrapper) = wrapper {
+            wrapper.retire();
+        }
+    }
+}
+
+/// Statistics of the node pool of a queue. Every counter is loaded with relaxed ordering, so the
+/// numbers are only approximate under concurrent use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NodePoolStats {
+    /// Enqueues that reused a node from the pool.
+    pub hits: usize,
+    /// Enqueues that had to allocate a new node because the pool was empty.
+    pub misses: usize,
+    /// Reclaimed nodes that were put back into the pool.
+    pub recycled: usize,
+    /// Reclaimed nodes that were freed because the pool was full.
+    pub released: usize,
+    /// Nodes that are currently idle in the pool.
+    pub idle: usize,
+}
+
+// Type erased view of a pool. The recycling deleter is a single static so it can only reach the
+// typed pool through the function pointer stored here, similar to how the runtime reaches its
+// tasks through Metadata.
+#[repr(C)]
+struct PoolHeader {
+    recycle: fn(*const PoolHeader, *mut ()),
+}
+
+#[repr(C)]
+struct NodePool<T> {
+    header: PoolHeader,
+    free: AtomicPtr<Node<T>>,
+    capacity: usize,
+    idle: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    recycled: AtomicUsize,
+    released: AtomicUsize,
+}
+
+unsafe impl<T: Send> Send for NodePool<T> {}
+unsafe impl<T: Send> Sync for NodePool<T> {}
+
+impl<T> Drop for NodePool<T> {
+    fn drop(&mut self) {
+        let mut current = self.free.load(Ordering::Acquire);
+        while !current.is_null() {
+            let next = unsafe { (*current).next.load(Ordering::Acquire) };
+            std::mem::drop(unsafe { Box::from_raw(current) });
+            current = next;
+        }
+    }
+}
+
+impl<T> NodePool<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            header: PoolHeader {
+                recycle: Self::recycle,
+            },
+            free: AtomicPtr::new(ptr::null_mut()),
+            capacity,
+            idle: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            recycled: AtomicUsize::new(0),
+            released: AtomicUsize::new(0),
+        }
+    }
+
+    // Popping from the free list is safe from ABA because the node on top is protected. A node
+    // only comes back into the free list through reclamation, which skips every protected node,
+    // so the node can not reappear on top while we are looking at it.
+    fn take(&self) -> Option<*mut Node<T>> {
+        loop {
+            let mut holder = Holder::default();
+            let guard = if let Some(guard) = unsafe { holder.load_pointer(&self.free) } {
+                guard
+            } else {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            };
+            let next = guard.next.load(Ordering::Acquire);
+            if self
+                .free
+                .compare_exchange(guard.data, next, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                self.idle.fetch_sub(1, Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                let node = guard.data;
+                unsafe {
+                    (*node).pool = ptr::null();
+                    (*node).claim = Claim::new();
+                    (*node).next.store(ptr::null_mut(), Ordering::Relaxed);
+                }
+                return Some(node);
+            }
+        }
+    }
+
+    fn put(&self, node: *mut Node<T>) {
+        if self.idle.fetch_add(1, Ordering::Relaxed) >= self.capacity {
+            self.idle.fetch_sub(1, Ordering::Relaxed);
+            self.released.fetch_add(1, Ordering::Relaxed);
+            std::mem::drop(unsafe { Box::from_raw(node) });
+            return;
+        }
+        self.recycled.fetch_add(1, Ordering::Relaxed);
+        let mut current = self.free.load(Ordering::Acquire);
+        loop {
+            unsafe { (*node).next.store(current, Ordering::Relaxed) };
+            match self
+                .free
+                .compare_exchange(current, node, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return,
+                Err(head) => current = head,
+            }
+        }
+    }
+
+    fn recycle(header: *const PoolHeader, node: *mut ()) {
+        let pool = unsafe { Arc::from_raw(header as *const NodePool<T>) };
+        pool.put(node as *mut Node<T>);
+    }
+
+    fn stats(&self) -> NodePoolStats {
+        NodePoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            recycled: self.recycled.load(Ordering::Relaxed),
+            released: self.released.load(Ordering::Relaxed),
+            idle: self.idle.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct Recycler;
+
+impl Deleter for Recycler {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn delete(&self, ptr: *mut dyn Uniform) {
+        if !ptr.is_null() {
+            // Every node starts with the pointer to its pool, see Node.
+            let header = unsafe { *(ptr as *mut *const PoolHeader) };
+            unsafe { ((*header).recycle)(header, ptr as *mut ()) };
+        }
+    }
 }
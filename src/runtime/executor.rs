@@ -1,12 +1,13 @@
 #![allow(unused)]
 
+use crate::channel::{self, Receiver, Sender};
 use crate::runtime::runtime::{Carrier, HIGH_QUEUE};
 use crate::runtime::waker::VTABLE;
 use std::cell::UnsafeCell;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex, mpsc};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
 
 // States of a task
@@ -20,7 +21,7 @@ where
     F: Future + Send + 'static,
     F::Output: Send + 'static,
 {
-    handle: mpsc::Receiver<F::Output>,
+    handle: Receiver<F::Output>,
     waker: Arc<Mutex<Option<Waker>>>,
 }
 
@@ -82,7 +83,7 @@ where
 {
     metadata: Metadata,
     future: UnsafeCell<Option<Pin<Box<F>>>>,
-    sender: mpsc::Sender<F::Output>,
+    sender: Sender<F::Output>,
     waker: Arc<Mutex<Option<Waker>>>,
 }
 
@@ -174,7 +175,7 @@ where
         drop_func: Task::<F>::drop_task,
     };
     let waker = Arc::new(Mutex::new(None));
-    let (tx, rx) = mpsc::channel::<F::Output>();
+    let (tx, rx) = channel::unbounded::<F::Output>();
     let task = Task {
         metadata,
         future: UnsafeCell::new(Some(Box::pin(future))),
@@ -1,3 +1,4 @@
 mod executor;
+#[allow(clippy::module_inception)]
 mod runtime;
 mod waker;
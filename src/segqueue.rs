@@ -0,0 +1,235 @@
+#![allow(unexpected_cfgs)]
+
+use crate::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use crate::queue::DequeueError;
+use crate::{BoxedPointer, Doer, Holder};
+
+static DROPBOX: BoxedPointer = BoxedPointer::new();
+
+#[cfg(not(loom))]
+const SEGMENT_SIZE: usize = 32;
+
+// Small enough for loom to also go through the appending and retiring of segments.
+#[cfg(loom)]
+const SEGMENT_SIZE: usize = 2;
+
+// States of a slot
+const EMPTY: usize = 0;
+const WRITTEN: usize = 1;
+const TAKEN: usize = 2;
+
+struct Slot<T> {
+    state: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+struct Segment<T> {
+    enqueue_index: AtomicUsize,
+    dequeue_index: AtomicUsize,
+    slots: [Slot<T>; SEGMENT_SIZE],
+    next: AtomicPtr<Segment<T>>,
+}
+
+impl<T> Segment<T> {
+    fn new() -> Self {
+        Self {
+            enqueue_index: AtomicUsize::new(0),
+            dequeue_index: AtomicUsize::new(0),
+            slots: std::array::from_fn(|_| Slot {
+                state: AtomicUsize::new(EMPTY),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            }),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    // A segment that gets appended already carries the value of the enqueuer appending it, so
+    // that the enqueuer does not have to race for a slot in it afterwards.
+    fn with_first(value: T) -> Self {
+        let segment = Self::new();
+        unsafe { (*segment.slots[0].value.get()).write(value) };
+        segment.slots[0].state.store(WRITTEN, Ordering::Relaxed);
+        segment.enqueue_index.store(1, Ordering::Relaxed);
+        segment
+    }
+}
+
+/// An unbounded queue made of a linked list of fixed size segments. Enqueuers and dequeuers claim
+/// slots inside of a segment with a fetch_add on its indices, so they only ever compete on the
+/// list itself when a segment is full or drained. The hazard pointers are only used for the
+/// segments and not for every single value.
+pub struct SegQueue<T> {
+    head: AtomicPtr<Segment<T>>,
+    tail: AtomicPtr<Segment<T>>,
+    marker: PhantomData<Segment<T>>,
+}
+
+unsafe impl<T> Send for SegQueue<T> where T: Send {}
+unsafe impl<T> Sync for SegQueue<T> where T: Send {}
+
+impl<T> Drop for SegQueue<T> {
+    fn drop(&mut self) {
+        let mut current = self.head.load(Ordering::Acquire);
+        while !current.is_null() {
+            let mut owned = unsafe { Box::from_raw(current) };
+            for slot in owned.slots.iter_mut() {
+                if slot.state.load(Ordering::Acquire) == WRITTEN {
+                    unsafe { slot.value.get_mut().assume_init_drop() };
+                }
+            }
+            current = owned.next.load(Ordering::Acquire);
+            std::mem::drop(owned);
+        }
+    }
+}
+
+impl<T> Default for SegQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SegQueue<T> {
+    pub fn new() -> Self {
+        let segment = Box::into_raw(Box::new(Segment::new()));
+        Self {
+            head: AtomicPtr::new(segment),
+            tail: AtomicPtr::new(segment),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn enqueue(&self, value: T) {
+        let mut value = value;
+        loop {
+            let mut holder = Holder::default();
+            let guard = unsafe {
+                holder
+                    .load_pointer(&self.tail)
+                    .expect("The tail segment is never null")
+            };
+            let segment = guard.data;
+            let index = guard.enqueue_index.fetch_add(1, Ordering::AcqRel);
+            if index >= SEGMENT_SIZE {
+                if self.tail.load(Ordering::Acquire) != segment {
+                    continue;
+                }
+                let next = guard.next.load(Ordering::Acquire);
+                if next.is_null() {
+                    let allocated = Box::into_raw(Box::new(Segment::with_first(value)));
+                    if guard
+                        .next
+                        .compare_exchange(
+                            ptr::null_mut(),
+                            allocated,
+                            Ordering::AcqRel,
+                            Ordering::Relaxed,
+                        )
+                        .is_ok()
+                    {
+                        let _ = self.tail.compare_exchange(
+                            segment,
+                            allocated,
+                            Ordering::AcqRel,
+                            Ordering::Relaxed,
+                        );
+                        return;
+                    }
+                    // Somebody else appended a segment first, so we take our value back out of
+                    // the one that never got published.
+                    let mut owned = unsafe { Box::from_raw(allocated) };
+                    value = unsafe { owned.slots[0].value.get_mut().assume_init_read() };
+                } else {
+                    let _ = self.tail.compare_exchange(
+                        segment,
+                        next,
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    );
+                }
+                continue;
+            }
+            let slot = &guard.slots[index];
+            unsafe { (*slot.value.get()).write(value) };
+            if slot
+                .state
+                .compare_exchange(EMPTY, WRITTEN, Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+            // A dequeuer reached this slot before we could fill it and has given up on it. The
+            // value was never seen by anyone else so we still own it and try another slot.
+            value = unsafe { (*slot.value.get()).assume_init_read() };
+        }
+    }
+
+    pub fn dequeue(&self) -> Result<T, DequeueError> {
+        loop {
+            let mut holder = Holder::default();
+            let guard = unsafe {
+                holder
+                    .load_pointer(&self.head)
+                    .expect("The head segment is never null")
+            };
+            let segment = guard.data;
+            if guard.dequeue_index.load(Ordering::Acquire)
+                >= guard.enqueue_index.load(Ordering::Acquire)
+                && guard.next.load(Ordering::Acquire).is_null()
+            {
+                return Err(DequeueError::Empty);
+            }
+            let index = guard.dequeue_index.fetch_add(1, Ordering::AcqRel);
+            if index >= SEGMENT_SIZE {
+                let next = guard.next.load(Ordering::Acquire);
+                if next.is_null() {
+                    return Err(DequeueError::Empty);
+                }
+                // The tail must never be left behind on a segment that is about to be retired.
+                let _ =
+                    self.tail
+                        .compare_exchange(segment, next, Ordering::AcqRel, Ordering::Relaxed);
+                if self
+                    .head
+                    .compare_exchange(segment, next, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    let mut swap_holder = Holder::default();
+                    let wrapper =
+                        unsafe { swap_holder.get_wrapper(&AtomicPtr::new(segment), &DROPBOX) };
+                    if let Some(mut wrapper) = wrapper {
+                        wrapper.retire();
+                    }
+                }
+                continue;
+            }
+            let slot = &guard.slots[index];
+            if slot.state.swap(TAKEN, Ordering::AcqRel) == WRITTEN {
+                return Ok(unsafe { (*slot.value.get()).assume_init_read() });
+            }
+            // The enqueuer that claimed this slot has not written to it yet. It will notice the
+            // slot being taken and move its value elsewhere.
+        }
+    }
+
+    /// Returns whether the queue looked empty at the point where the indices of the head
+    /// segment were read.
+    pub fn is_empty(&self) -> bool {
+        let mut holder = Holder::default();
+        let guard = unsafe {
+            holder
+                .load_pointer(&self.head)
+                .expect("The head segment is never null")
+        };
+        let dequeue_index = guard.dequeue_index.load(Ordering::Acquire);
+        let enqueue_index = guard.enqueue_index.load(Ordering::Acquire);
+        dequeue_index >= enqueue_index.min(SEGMENT_SIZE)
+            && guard.next.load(Ordering::Acquire).is_null()
+    }
+}
@@ -0,0 +1,609 @@
+#![allow(unexpected_cfgs)]
+
+use crate::priorityqueue::{MAX_LEVEL, random_level};
+use crate::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds, RangeFull};
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use crate::{BoxedPointer, Doer, Holder, HolderArray};
+
+static DROPBOX: BoxedPointer = BoxedPointer::new();
+
+// The lowest bit of a next pointer marks the node that owns the pointer as deleted on that level.
+const MARK: usize = 1;
+
+fn is_marked<T>(ptr: *mut T) -> bool {
+    ptr as usize & MARK == MARK
+}
+
+fn marked<T>(ptr: *mut T) -> *mut T {
+    ptr.map_addr(|addr| addr | MARK)
+}
+
+fn unmarked<T>(ptr: *mut T) -> *mut T {
+    ptr.map_addr(|addr| addr & !MARK)
+}
+
+fn above<Q: Ord + ?Sized>(bound: Bound<&Q>, key: &Q) -> bool {
+    match bound {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+fn below<Q: Ord + ?Sized>(bound: Bound<&Q>, key: &Q) -> bool {
+    match bound {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+struct Node<K, V> {
+    key: K,
+    // Taking the value out is what removes the entry. The node is only marked and unlinked after
+    // that, so a node without a value is one that is on its way out.
+    value: AtomicPtr<V>,
+    next: Box<[AtomicPtr<Node<K, V>>]>,
+    // One reference for the inserter and one for every level that the node is linked on. The
+    // node gets retired once the last of them is gone, so it is never retired while it can still
+    // be reached on any level.
+    refs: AtomicUsize,
+}
+
+impl<K, V> Drop for Node<K, V> {
+    fn drop(&mut self) {
+        let value = self.value.load(Ordering::Acquire);
+        if !value.is_null() {
+            std::mem::drop(unsafe { Box::from_raw(value) });
+        }
+    }
+}
+
+// The holders that keep the predecessors and successors of a search protected.
+struct Holders {
+    preds: HolderArray<MAX_LEVEL>,
+    succs: HolderArray<MAX_LEVEL>,
+}
+
+impl Holders {
+    fn new() -> Self {
+        Self {
+            preds: HolderArray::new(),
+            succs: HolderArray::new(),
+        }
+    }
+}
+
+// Where a search ended on every level. A null predecessor stands for the head.
+struct Position<K, V> {
+    preds: [*mut Node<K, V>; MAX_LEVEL],
+    succs: [*mut Node<K, V>; MAX_LEVEL],
+}
+
+/// A concurrent ordered map built on a lock-free skiplist, using the same towers of marked next
+/// pointers as the PriorityQueue. An entry is removed by taking the value out of its node, after
+/// which the node is marked on every level and unlinked by a search.
+///
+/// Values live in their own allocations so that insert can replace them in place. Both nodes
+/// and values are reclaimed through the hazard domain, and the entries that the map hands out
+/// keep both protected, so they stay readable even after they have been removed or replaced.
+/// Whichever thread reclaims them drops the keys and values inside, so both have to be Send and
+/// 'static.
+pub struct SkipMap<K, V> {
+    head: [AtomicPtr<Node<K, V>>; MAX_LEVEL],
+    len: AtomicUsize,
+    marker: PhantomData<Node<K, V>>,
+}
+
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for SkipMap<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for SkipMap<K, V> {}
+
+impl<K, V> Drop for SkipMap<K, V> {
+    fn drop(&mut self) {
+        // A node that got removed while it was being linked on the upper levels might only be
+        // reachable from there, so every level is walked. Nodes that are not reachable anymore
+        // have already been retired.
+        let mut nodes = HashSet::new();
+        for level in 0..MAX_LEVEL {
+            let mut current = unmarked(self.head[level].load(Ordering::Acquire));
+            while !current.is_null() {
+                nodes.insert(current);
+                current = unmarked(unsafe { (*current).next[level].load(Ordering::Acquire) });
+            }
+        }
+        for node in nodes {
+            std::mem::drop(unsafe { Box::from_raw(node) });
+        }
+    }
+}
+
+impl<K: Ord + Send + 'static, V: Send + 'static> Default for SkipMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Send + 'static, V: Send + 'static> SkipMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            head: std::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+            len: AtomicUsize::new(0),
+            marker: PhantomData,
+        }
+    }
+
+    /// Inserts the entry, replacing the value of the key if there is one. Returns whether the key
+    /// was absent. A replaced value stays readable through the entries that still hold it.
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let height = random_level();
+        let node = Box::into_raw(Box::new(Node {
+            key,
+            value: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            next: (0..height)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            refs: AtomicUsize::new(1),
+        }));
+        // The reference of the inserter keeps the node alive until we are done with it.
+        let key = unsafe { &(*node).key };
+        let mut holders = Holders::new();
+        let mut position = self.find(|k| k >= key, &mut holders);
+        loop {
+            let curr = position.succs[0];
+            if !curr.is_null() && unsafe { &(*curr).key } == key {
+                let current = unsafe { (*curr).value.load(Ordering::Acquire) };
+                if current.is_null() {
+                    // The entry is being removed, so we help to unlink it and try again.
+                    let mut holder = Holder::default();
+                    std::mem::swap(&mut holder, &mut holders.succs[0]);
+                    self.unlink(curr, &mut holders);
+                    position = self.find(|k| k >= key, &mut holders);
+                    continue;
+                }
+                let value = unsafe { (*node).value.load(Ordering::Relaxed) };
+                if unsafe { &(*curr).value }
+                    .compare_exchange(current, value, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    Self::retire(current);
+                    // Our node was never published and gives up its value to the existing one.
+                    unsafe { (*node).value.store(ptr::null_mut(), Ordering::Relaxed) };
+                    std::mem::drop(unsafe { Box::from_raw(node) });
+                    return false;
+                }
+                continue;
+            }
+            unsafe { (*node).next[0].store(curr, Ordering::Relaxed) };
+            unsafe { (*node).refs.fetch_add(1, Ordering::Relaxed) };
+            // Counted before the node is published so that a remove can not take the count
+            // below zero.
+            self.len.fetch_add(1, Ordering::Relaxed);
+            if self
+                .next(position.preds[0], 0)
+                .compare_exchange(curr, node, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            unsafe { (*node).refs.fetch_sub(1, Ordering::Relaxed) };
+            position = self.find(|k| k >= key, &mut holders);
+        }
+        'levels: for level in 1..height {
+            loop {
+                let succ = position.succs[level];
+                // The next pointer is set with a compare_exchange so that we notice the node
+                // getting removed and stop linking it any further.
+                let current = unsafe { (*node).next[level].load(Ordering::Acquire) };
+                if is_marked(current) {
+                    break 'levels;
+                }
+                if current != succ
+                    && unsafe { &(*node).next[level] }
+                        .compare_exchange(current, succ, Ordering::AcqRel, Ordering::Relaxed)
+                        .is_err()
+                {
+                    break 'levels;
+                }
+                unsafe { (*node).refs.fetch_add(1, Ordering::Relaxed) };
+                if self
+                    .next(position.preds[level], level)
+                    .compare_exchange(succ, node, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break;
+                }
+                unsafe { (*node).refs.fetch_sub(1, Ordering::Relaxed) };
+                position = self.find(|k| k >= key, &mut holders);
+            }
+        }
+        // A node that was removed while we were linking it might have been linked on a level
+        // after the remover unlinked it there, so we unlink it ourselves.
+        if is_marked(unsafe { (*node).next[0].load(Ordering::Acquire) }) {
+            self.find(|k| k >= key, &mut holders);
+        }
+        std::mem::drop(holders);
+        self.release(node);
+        true
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<Entry<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut holders = Holders::new();
+        let position = self.find(|k| k.borrow() >= key, &mut holders);
+        let curr = position.succs[0];
+        if curr.is_null() || unsafe { (*curr).key.borrow() } != key {
+            return None;
+        }
+        let mut holder = Holder::default();
+        std::mem::swap(&mut holder, &mut holders.succs[0]);
+        Entry::new(holder, curr).ok()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Removes the entry of the key. Returns whether there was one.
+    pub fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut holders = Holders::new();
+        let position = self.find(|k| k.borrow() >= key, &mut holders);
+        let curr = position.succs[0];
+        if curr.is_null() || unsafe { (*curr).key.borrow() } != key {
+            return false;
+        }
+        let mut holder = Holder::default();
+        std::mem::swap(&mut holder, &mut holders.succs[0]);
+        let removed = self.take(curr, &mut Holder::default()).is_some();
+        // Even if somebody else got to the value first, we help them unlink the node.
+        self.unlink(curr, &mut holders);
+        removed
+    }
+
+    /// Returns the entry with the lowest key.
+    pub fn first(&self) -> Option<Entry<'_, K, V>> {
+        let mut holders = Holders::new();
+        loop {
+            let position = self.find(|_| true, &mut holders);
+            let curr = position.succs[0];
+            if curr.is_null() {
+                return None;
+            }
+            let mut holder = Holder::default();
+            std::mem::swap(&mut holder, &mut holders.succs[0]);
+            // The holder has to keep the node protected until it has been unlinked.
+            let _holder = match Entry::new(holder, curr) {
+                Ok(entry) => return Some(entry),
+                Err(holder) => holder,
+            };
+            self.unlink(curr, &mut holders);
+        }
+    }
+
+    /// Returns the entry with the highest key.
+    pub fn last(&self) -> Option<Entry<'_, K, V>> {
+        let mut holders = Holders::new();
+        loop {
+            // A search that never stops ends with the last node as the predecessor on the
+            // bottom level.
+            let position = self.find(|_| false, &mut holders);
+            let pred = position.preds[0];
+            if pred.is_null() {
+                return None;
+            }
+            let mut holder = Holder::default();
+            std::mem::swap(&mut holder, &mut holders.preds[0]);
+            // The predecessor might only have been protected on the way down from a level above
+            // without being validated, so we make sure that it was not removed before the
+            // protection of that level goes away.
+            if is_marked(unsafe { (*pred).next[0].load(Ordering::Acquire) }) {
+                continue;
+            }
+            let _holder = match Entry::new(holder, pred) {
+                Ok(entry) => return Some(entry),
+                Err(holder) => holder,
+            };
+            self.unlink(pred, &mut holders);
+        }
+    }
+
+    /// Removes the entry with the lowest key and returns it.
+    pub fn pop_first(&self) -> Option<Entry<'_, K, V>> {
+        let mut holders = Holders::new();
+        loop {
+            let position = self.find(|_| true, &mut holders);
+            let curr = position.succs[0];
+            if curr.is_null() {
+                return None;
+            }
+            let mut holder = Holder::default();
+            std::mem::swap(&mut holder, &mut holders.succs[0]);
+            let mut value_holder = Holder::default();
+            let taken = self.take(curr, &mut value_holder);
+            self.unlink(curr, &mut holders);
+            if let Some(value) = taken {
+                return Some(Entry {
+                    _node_holder: holder,
+                    _value_holder: value_holder,
+                    node: curr,
+                    value,
+                    marker: PhantomData,
+                });
+            }
+        }
+    }
+
+    /// Returns an iterator over the entries with keys in the range, in ascending order. The
+    /// iterator is weakly consistent. Entries inserted or removed during the iteration might or
+    /// might not be seen, but no key is ever seen twice.
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V, R, Q>
+    where
+        K: Borrow<Q>,
+        R: RangeBounds<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut holders = Holders::new();
+        let position = self.find(|k| above(range.start_bound(), k.borrow()), &mut holders);
+        let mut holder = Holder::default();
+        std::mem::swap(&mut holder, &mut holders.succs[0]);
+        Range {
+            map: self,
+            range,
+            next: position.succs[0],
+            holder,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn iter(&self) -> Range<'_, K, V, RangeFull, K> {
+        self.range(..)
+    }
+
+    /// Returns the number of entries. It is only an approximation under concurrent use.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.first().is_none()
+    }
+
+    fn next(&self, pred: *mut Node<K, V>, level: usize) -> &AtomicPtr<Node<K, V>> {
+        if pred.is_null() {
+            &self.head[level]
+        } else {
+            unsafe { &(*pred).next[level] }
+        }
+    }
+
+    // Removes the entry of the node by taking its value. The value gets protected before it is
+    // taken, so that it stays readable for as long as the holder protects it.
+    fn take(&self, node: *mut Node<K, V>, holder: &mut Holder) -> Option<*mut V> {
+        let value = unsafe { &(*node).value };
+        loop {
+            let current = holder.protect_load(value);
+            if current.is_null() {
+                return None;
+            }
+            if value
+                .compare_exchange(
+                    current,
+                    ptr::null_mut(),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                Self::retire(current);
+                return Some(current);
+            }
+        }
+    }
+
+    // Marks a node whose value has been taken on every level, top down so that an inserter that
+    // is still linking it stops, and then unlinks it through a search. The node has to be
+    // protected by a holder other than the ones passed in.
+    fn unlink(&self, node: *mut Node<K, V>, holders: &mut Holders) {
+        let next = unsafe { &(*node).next };
+        for level in (0..next.len()).rev() {
+            let mut current = next[level].load(Ordering::Acquire);
+            while !is_marked(current) {
+                match next[level].compare_exchange(
+                    current,
+                    marked(current),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => break,
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+        let key = unsafe { &(*node).key };
+        self.find(|k| k >= key, holders);
+    }
+
+    // Searches for the position of the first key that passes the check on every level and
+    // unlinks the marked nodes on the way, the same way as the search of the PriorityQueue.
+    //
+    // The predecessors and successors stay protected in the holders until the next search.
+    fn find(&self, stop: impl Fn(&K) -> bool, holders: &mut Holders) -> Position<K, V> {
+        let mut position = Position {
+            preds: [ptr::null_mut(); MAX_LEVEL],
+            succs: [ptr::null_mut(); MAX_LEVEL],
+        };
+        'retry: loop {
+            let mut pred: *mut Node<K, V> = ptr::null_mut();
+            for level in (0..MAX_LEVEL).rev() {
+                // The predecessor is still protected by the holder of the level above.
+                holders.preds[level].protect(pred);
+                let mut curr = self.next(pred, level).load(Ordering::Acquire);
+                loop {
+                    if is_marked(curr) {
+                        // The predecessor is being removed on this level.
+                        continue 'retry;
+                    }
+                    if curr.is_null() {
+                        break;
+                    }
+                    holders.succs[level].protect(curr);
+                    if self.next(pred, level).load(Ordering::Acquire) != curr {
+                        continue 'retry;
+                    }
+                    let succ = unsafe { (*curr).next[level].load(Ordering::Acquire) };
+                    if is_marked(succ) {
+                        if self
+                            .next(pred, level)
+                            .compare_exchange(
+                                curr,
+                                unmarked(succ),
+                                Ordering::AcqRel,
+                                Ordering::Relaxed,
+                            )
+                            .is_err()
+                        {
+                            continue 'retry;
+                        }
+                        self.release(curr);
+                        curr = unmarked(succ);
+                        continue;
+                    }
+                    if stop(unsafe { &(*curr).key }) {
+                        break;
+                    }
+                    pred = curr;
+                    let (preds, succs) = (&mut holders.preds, &mut holders.succs);
+                    std::mem::swap(&mut preds[level], &mut succs[level]);
+                    curr = succ;
+                }
+                position.preds[level] = pred;
+                position.succs[level] = curr;
+            }
+            return position;
+        }
+    }
+
+    // Drops one reference of the node and retires it once there are none left.
+    fn release(&self, node: *mut Node<K, V>) {
+        if unsafe { (*node).refs.fetch_sub(1, Ordering::AcqRel) } == 1 {
+            Self::retire(node);
+        }
+    }
+
+    fn retire<T>(ptr: *mut T) {
+        let mut holder = Holder::default();
+        let wrapper = unsafe { holder.get_wrapper(&AtomicPtr::new(ptr), &DROPBOX) };
+        if let Some(mut wrapper) = wrapper {
+            wrapper.retire();
+        }
+    }
+}
+
+/// An entry of a SkipMap. Its key and value are protected from being reclaimed for as long as
+/// the entry lives, even if the entry gets removed from the map or its value gets replaced.
+pub struct Entry<'a, K, V> {
+    // Only held on to for the protections, which end when they get dropped.
+    _node_holder: Holder,
+    _value_holder: Holder,
+    node: *mut Node<K, V>,
+    value: *mut V,
+    marker: PhantomData<&'a SkipMap<K, V>>,
+}
+
+impl<K, V> Entry<'_, K, V> {
+    // Protects the value of a node that is protected by the holder. There is no entry for a node
+    // whose value has been taken, in which case the holder is handed back so that the node stays
+    // protected for as long as the caller still needs it.
+    fn new(holder: Holder, node: *mut Node<K, V>) -> Result<Self, Holder> {
+        let mut value_holder = Holder::default();
+        let value = value_holder.protect_load(unsafe { &(*node).value });
+        if value.is_null() {
+            return Err(holder);
+        }
+        Ok(Self {
+            _node_holder: holder,
+            _value_holder: value_holder,
+            node,
+            value,
+            marker: PhantomData,
+        })
+    }
+
+    pub fn key(&self) -> &K {
+        unsafe { &(*self.node).key }
+    }
+
+    pub fn value(&self) -> &V {
+        unsafe { &*self.value }
+    }
+}
+
+/// An iterator over a range of the entries of a SkipMap, see SkipMap::range.
+pub struct Range<'a, K, V, R, Q: ?Sized> {
+    map: &'a SkipMap<K, V>,
+    range: R,
+    // The node that comes next, which is protected by the holder.
+    next: *mut Node<K, V>,
+    holder: Holder,
+    marker: PhantomData<fn(&Q)>,
+}
+
+impl<'a, K, V, R, Q> Iterator for Range<'a, K, V, R, Q>
+where
+    K: Ord + Borrow<Q> + Send + 'static,
+    V: Send + 'static,
+    R: RangeBounds<Q>,
+    Q: Ord + ?Sized,
+{
+    type Item = Entry<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let curr = self.next;
+            if curr.is_null() {
+                return None;
+            }
+            let key = unsafe { &(*curr).key };
+            if !below(self.range.end_bound(), key.borrow()) {
+                self.next = ptr::null_mut();
+                return None;
+            }
+            let mut holder = Holder::default();
+            let succ = holder.protect_load(unsafe { &(*curr).next[0] });
+            if is_marked(succ) {
+                // The node has been removed, so its successor might already be gone as well. We
+                // skip it and carry on from the first node after its key instead.
+                let mut holders = Holders::new();
+                let position = self.map.find(|k| k > key, &mut holders);
+                self.next = position.succs[0];
+                std::mem::swap(&mut self.holder, &mut holders.succs[0]);
+                continue;
+            }
+            // The hazard of our holder moves into the entry along with the node that it protects,
+            // so the node never goes unprotected.
+            self.next = succ;
+            let holder = std::mem::replace(&mut self.holder, holder);
+            if let Ok(entry) = Entry::new(holder, curr) {
+                return Some(entry);
+            }
+        }
+    }
+}
@@ -1,26 +1,43 @@
-use crate::sync::atomic::AtomicPtr;
-use crate::{BoxedPointer, Doer, Holder};
+use crate::claim::Claim;
+use crate::queue::Formatted;
+use crate::sync::atomic::{AtomicPtr, AtomicUsize};
+use crate::{BoxedPointer, CachePadded, Doer, Holder};
 use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
 use std::sync::atomic::Ordering;
 
 static DROPBOX: BoxedPointer = BoxedPointer::new();
 
+// The value is moved out by delete before the node gets retired, so the node itself must never
+// drop it. Values still in the stack are dropped by the drop implementation of the stack.
 struct Node<T> {
-    value: T,
+    value: ManuallyDrop<T>,
     next: AtomicPtr<Node<T>>,
+    claim: Claim,
 }
 
-impl<T: Clone> Node<T> {
+impl<T> Node<T> {
     fn new(value: T) -> Self {
         Self {
-            value,
+            value: ManuallyDrop::new(value),
             next: AtomicPtr::new(std::ptr::null_mut()),
+            claim: Claim::new(),
         }
     }
 }
 
+/// A lock-free LIFO stack in the style of Treiber. Nodes are reclaimed through the hazard
+/// domain.
+///
+/// Reading a value in place, through peek_with or the Debug and Clone implementations, is the
+/// one exception to the stack being lock-free. A delete that takes a value while such a read of
+/// that same value runs waits for the read to finish before moving the value out. Inserts and
+/// deletes of other values are never held up.
 pub struct Stack<T> {
-    head: AtomicPtr<Node<T>>,
+    // Kept on a cache line of its own, so that whatever the stack sits next to does not add to
+    // the traffic on it.
+    head: CachePadded<AtomicPtr<Node<T>>>,
+    len: AtomicUsize,
     marker: PhantomData<Node<T>>,
 }
 
@@ -32,31 +49,123 @@ impl<T> Drop for Stack<T> {
         let mut current = self.head.load(Ordering::Acquire);
         while !current.is_null() {
             let next = unsafe { (*current).next.load(Ordering::Acquire) };
-            let owned = unsafe { Box::from_raw(current) };
+            let mut owned = unsafe { Box::from_raw(current) };
+            unsafe { ManuallyDrop::drop(&mut owned.value) };
             std::mem::drop(owned);
             current = next;
         }
     }
 }
 
-impl<T: Clone> Default for Stack<T> {
+impl<T> Default for Stack<T> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<T: Clone> Stack<T> {
+impl<T> FromIterator<T> for Stack<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut stack = Stack::new();
+        stack.extend(iter);
+        stack
+    }
+}
+
+// Having a mutable reference means that no other thread can be touching the stack, so the values
+// are pushed directly rather than through insert which could give up under contention.
+impl<T> Extend<T> for Stack<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            let boxed = Box::into_raw(Box::new(Node::new(value)));
+            let current_head = self.head.load(Ordering::Relaxed);
+            unsafe { (*boxed).next.store(current_head, Ordering::Relaxed) };
+            self.head.store(boxed, Ordering::Release);
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An owning iterator over the values of a stack in LIFO order.
+pub struct IntoIter<T> {
+    stack: Stack<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    // The stack is owned by the iterator so there are no other threads around and the nodes can
+    // be unlinked and freed directly instead of going through the hazard domain.
+    fn next(&mut self) -> Option<T> {
+        let head = self.stack.head.load(Ordering::Acquire);
+        if head.is_null() {
+            return None;
+        }
+        let mut owned = unsafe { Box::from_raw(head) };
+        self.stack
+            .head
+            .store(owned.next.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.stack.len.fetch_sub(1, Ordering::Relaxed);
+        Some(unsafe { ManuallyDrop::take(&mut owned.value) })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.stack.len();
+        (len, Some(len))
+    }
+}
+
+impl<T> IntoIterator for Stack<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { stack: self }
+    }
+}
+
+impl<T: std::fmt::Debug + Sync> std::fmt::Debug for Stack<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let alternate = f.alternate();
+        let values = self.snapshot(|value| {
+            if alternate {
+                format!("{:#?}", value)
+            } else {
+                format!("{:?}", value)
+            }
+        });
+        f.debug_list()
+            .entries(values.iter().map(|value| Formatted(value)))
+            .finish()
+    }
+}
+
+impl<T: Clone + Sync> Clone for Stack<T> {
+    fn clone(&self) -> Self {
+        // The snapshot goes from the top to the bottom, so it is pushed back in reverse.
+        self.snapshot(T::clone).into_iter().rev().collect()
+    }
+}
+
+impl<T> Stack<T> {
     pub fn new() -> Self {
         Self {
-            head: AtomicPtr::new(std::ptr::null_mut()),
+            head: CachePadded::new(AtomicPtr::new(std::ptr::null_mut())),
+            len: AtomicUsize::new(0),
             marker: PhantomData,
         }
     }
 
     pub fn insert(&self, value: T) -> Result<&str, &str> {
         let mut attempts = 0;
+        let boxed = Box::into_raw(Box::new(Node::new(value)));
+        // The counter is bumped before the node is published so that a concurrent delete of
+        // this very node can never decrement it below zero.
+        self.len.fetch_add(1, Ordering::Relaxed);
         loop {
             if attempts > 15 {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                let mut owned = unsafe { Box::from_raw(boxed) };
+                unsafe { ManuallyDrop::drop(&mut owned.value) };
                 return Err("Insertion failed. Try again!");
             }
             let mut holder = Holder::default();
@@ -66,9 +175,7 @@ impl<T: Clone> Stack<T> {
             } else {
                 std::ptr::null_mut()
             };
-            let new_node = Node::new(value.clone());
-            new_node.next.store(current_head, Ordering::Release);
-            let boxed = Box::into_raw(Box::new(new_node));
+            unsafe { (*boxed).next.store(current_head, Ordering::Release) };
             if self
                 .head
                 .compare_exchange(current_head, boxed, Ordering::AcqRel, Ordering::Relaxed)
@@ -76,8 +183,6 @@ impl<T: Clone> Stack<T> {
             {
                 return Ok("Insertion successful!");
             } else {
-                let owned = unsafe { Box::from_raw(boxed) };
-                std::mem::drop(owned);
                 attempts += 1;
             }
         }
@@ -100,12 +205,15 @@ impl<T: Clone> Stack<T> {
                 return Err("There are no elements in the list");
             }
             let next_head = unsafe { (*current_head).next.load(Ordering::Acquire) };
+            // SeqCst pairs with the readers of the value, see Claim.
             if self
                 .head
-                .compare_exchange(current_head, next_head, Ordering::AcqRel, Ordering::Relaxed)
+                .compare_exchange(current_head, next_head, Ordering::SeqCst, Ordering::Relaxed)
                 .is_ok()
             {
-                let value = unsafe { std::ptr::read(&(*current_head).value) };
+                unsafe { (*current_head).claim.take() };
+                let value = unsafe { ManuallyDrop::take(&mut (*current_head).value) };
+                self.len.fetch_sub(1, Ordering::Relaxed);
                 let mut holder = Holder::default();
                 let wrapper =
                     unsafe { holder.get_wrapper(&AtomicPtr::new(current_head), &DROPBOX) };
@@ -116,4 +224,74 @@ impl<T: Clone> Stack<T> {
             }
         }
     }
+
+    /// Returns the number of values in the stack. The counter is updated with relaxed ordering
+    /// around the operations, so under concurrent use it is only an approximation.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the stack had no values at the point where the head was read.
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire).is_null()
+    }
+
+    /// Calls f with a reference to the value on top of the stack without removing it. A delete
+    /// of that value waits for f to return before moving it out, so f should be short. In
+    /// particular f must not delete from the same stack, which would wait for f itself and never
+    /// return.
+    pub fn peek_with<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&T) -> R,
+        T: Sync,
+    {
+        loop {
+            let mut holder = Holder::default();
+            let guard = unsafe { holder.load_pointer(&self.head) }?;
+            // An insert on top of the node also moves the head, which only costs a retry.
+            let linked = || self.head.load(Ordering::SeqCst) == guard.data;
+            if let Some(_reader) = guard.claim.enter(linked) {
+                return Some(f(&guard.value));
+            }
+        }
+    }
+
+    // Maps every value from the top to the bottom. As long as the head has not moved, nothing
+    // below it can have been deleted, so the walk validates the head after protecting each node
+    // and starts over once it moves. It therefore only finishes once it gets through the whole
+    // stack without a concurrent insert or delete, which is meant for a stack that is quiescent.
+    fn snapshot<F, R>(&self, mut f: F) -> Vec<R>
+    where
+        F: FnMut(&T) -> R,
+    {
+        let mut values = Vec::new();
+        'walk: loop {
+            values.clear();
+            let mut head_holder = Holder::default();
+            let head = if let Some(guard) = unsafe { head_holder.load_pointer(&self.head) } {
+                guard
+            } else {
+                return values;
+            };
+            let mut holder = Holder::default();
+            let mut current = head.data;
+            loop {
+                let linked = || self.head.load(Ordering::SeqCst) == head.data;
+                if let Some(_reader) = unsafe { (*current).claim.enter(linked) } {
+                    values.push(f(unsafe { &(*current).value }));
+                } else {
+                    continue 'walk;
+                }
+                let next = unsafe { (*current).next.load(Ordering::Acquire) };
+                if next.is_null() {
+                    return values;
+                }
+                holder.protect(next);
+                if self.head.load(Ordering::Acquire) != head.data {
+                    continue 'walk;
+                }
+                current = next;
+            }
+        }
+    }
 }
@@ -2,10 +2,20 @@
 
 #[cfg(loom)]
 pub mod atomic {
-    pub use loom::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+    pub use loom::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, fence};
 }
 
 #[cfg(not(loom))]
 pub mod atomic {
-    pub use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+    pub use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, fence};
+}
+
+#[cfg(loom)]
+pub mod thread {
+    pub use loom::thread::yield_now;
+}
+
+#[cfg(not(loom))]
+pub mod thread {
+    pub use std::thread::yield_now;
 }
@@ -0,0 +1,90 @@
+#![allow(unexpected_cfgs)]
+
+use crate::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering;
+
+/// An atomic pointer that carries a tag of BITS bits in the low bits of the address, which the
+/// alignment of T leaves free. Lock-free algorithms use such tags to mark nodes as deleted or to
+/// version pointers. All operations take and return the pointer and the tag separately, and a
+/// compare_exchange only succeeds if both match.
+///
+/// Holder::load_tagged protects the untagged address and hands out the tag next to the guard.
+pub struct TaggedPtr<T, const BITS: usize> {
+    inner: AtomicPtr<T>,
+}
+
+impl<T, const BITS: usize> Default for TaggedPtr<T, BITS> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T, const BITS: usize> TaggedPtr<T, BITS> {
+    const MASK: usize = {
+        assert!(
+            1 << BITS <= std::mem::align_of::<T>(),
+            "The alignment of T does not leave enough bits for the tag"
+        );
+        (1 << BITS) - 1
+    };
+
+    pub fn new(ptr: *mut T, tag: usize) -> Self {
+        Self {
+            inner: AtomicPtr::new(Self::pack(ptr, tag)),
+        }
+    }
+
+    pub fn null() -> Self {
+        Self::new(std::ptr::null_mut(), 0)
+    }
+
+    pub fn load(&self, order: Ordering) -> (*mut T, usize) {
+        Self::unpack(self.inner.load(order))
+    }
+
+    pub fn store(&self, ptr: *mut T, tag: usize, order: Ordering) {
+        self.inner.store(Self::pack(ptr, tag), order);
+    }
+
+    pub fn swap(&self, ptr: *mut T, tag: usize, order: Ordering) -> (*mut T, usize) {
+        Self::unpack(self.inner.swap(Self::pack(ptr, tag), order))
+    }
+
+    /// Replaces the pointer and the tag if both of them are the current ones. Returns the
+    /// previous ones on success and the actual ones on failure.
+    pub fn compare_exchange(
+        &self,
+        current: (*mut T, usize),
+        new: (*mut T, usize),
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<(*mut T, usize), (*mut T, usize)> {
+        self.inner
+            .compare_exchange(
+                Self::pack(current.0, current.1),
+                Self::pack(new.0, new.1),
+                success,
+                failure,
+            )
+            .map(Self::unpack)
+            .map_err(Self::unpack)
+    }
+
+    /// Returns the underlying atomic, whose value has the tag in it.
+    pub(crate) fn as_atomic(&self) -> &AtomicPtr<T> {
+        &self.inner
+    }
+
+    pub(crate) fn pack(ptr: *mut T, tag: usize) -> *mut T {
+        debug_assert!(ptr as usize & Self::MASK == 0, "The pointer is misaligned");
+        debug_assert!(tag <= Self::MASK, "The tag does not fit into {} bits", BITS);
+        ptr.map_addr(|addr| addr | (tag & Self::MASK))
+    }
+
+    pub(crate) fn unpack(ptr: *mut T) -> (*mut T, usize) {
+        (
+            ptr.map_addr(|addr| addr & !Self::MASK),
+            ptr as usize & Self::MASK,
+        )
+    }
+}
@@ -1,73 +1,1050 @@
-use crate::Queue;
+use crate::deque::{self, Steal, Stealer};
+use crate::queue::{Closed, DequeueError};
+use crate::wait::{WaitList, Waiter};
+use crate::{Queue, ShardedCounter};
+use std::any::Any;
+use std::cell::{Cell, UnsafeCell};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
 use std::panic::{self, AssertUnwindSafe};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 type Task = Box<dyn FnOnce() + Send + 'static>;
 
+/// The error returned by joining a task that panicked. It carries the payload of the panic.
+///
+/// A task that a shutdown discarded before it could run fails the same way, see is_discarded.
+pub struct TaskPanicked(Box<dyn Any + Send + 'static>);
+
+// The payload of a task that has been dropped without running.
+struct Discarded;
+
+// What a scope panics with if one of its tasks has been dropped without running.
+const DISCARDED: &str = "The task was discarded before it ran";
+
+impl TaskPanicked {
+    /// Returns whether the task never ran because it was discarded by a shutdown of the pool.
+    pub fn is_discarded(&self) -> bool {
+        self.0.is::<Discarded>()
+    }
+
+    /// Returns the payload of the panic, for example to resume it with
+    /// std::panic::resume_unwind.
+    pub fn into_panic(self) -> Box<dyn Any + Send + 'static> {
+        self.0
+    }
+
+    // The message of the panic, if it was raised with one.
+    fn message(&self) -> Option<&str> {
+        self.0
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| self.0.downcast_ref::<String>().map(String::as_str))
+    }
+}
+
+impl fmt::Debug for TaskPanicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_discarded() {
+            return f.write_str("TaskPanicked(Discarded)");
+        }
+        match self.message() {
+            Some(message) => f.debug_tuple("TaskPanicked").field(&message).finish(),
+            None => f.debug_tuple("TaskPanicked").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl fmt::Display for TaskPanicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_discarded() {
+            return f.write_str(DISCARDED);
+        }
+        match self.message() {
+            Some(message) => write!(f, "The task panicked: {}", message),
+            None => f.write_str("The task panicked"),
+        }
+    }
+}
+
+impl std::error::Error for TaskPanicked {}
+
+// The outcome of a submitted task, shared between the worker that runs it and the handle. The
+// result is written before finished is set and only read by the handle after it has seen
+// finished set, so the two never touch the cell at the same time.
+struct Completion<R> {
+    finished: AtomicBool,
+    result: UnsafeCell<Option<thread::Result<R>>>,
+    waiters: WaitList,
+}
+
+unsafe impl<R: Send> Send for Completion<R> {}
+unsafe impl<R: Send> Sync for Completion<R> {}
+
+impl<R> Completion<R> {
+    fn complete(&self, result: thread::Result<R>) {
+        unsafe { *self.result.get() = Some(result) };
+        // SeqCst pairs with the registration of the waiters, see WaitList.
+        self.finished.store(true, Ordering::SeqCst);
+        self.waiters.notify(usize::MAX);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+}
+
+// Completes a submitted task with Discarded if it gets dropped without having run, so that its
+// handle does not wait forever.
+struct CompleteOnDrop<R>(Option<Arc<Completion<R>>>);
+
+impl<R> Drop for CompleteOnDrop<R> {
+    fn drop(&mut self) {
+        if let Some(completion) = self.0.take() {
+            completion.complete(Err(Box::new(Discarded)));
+        }
+    }
+}
+
+impl<R> CompleteOnDrop<R> {
+    fn complete(mut self, result: thread::Result<R>) {
+        if let Some(completion) = self.0.take() {
+            completion.complete(result);
+        }
+    }
+}
+
+/// A handle to the result of a task handed to ThreadPool::submit. The result can be waited for
+/// by joining the handle or by awaiting it. Dropping the handle detaches the task, which still
+/// runs to completion.
+pub struct TaskHandle<R> {
+    completion: Arc<Completion<R>>,
+    // The waiter of the task that last polled the handle.
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<R> Drop for TaskHandle<R> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            self.completion.waiters.cancel(&waiter);
+        }
+    }
+}
+
+impl<R> fmt::Debug for TaskHandle<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskHandle")
+            .field("finished", &self.is_finished())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R> TaskHandle<R> {
+    /// Blocks until the task has finished and returns its result, or the payload of its panic.
+    pub fn join(mut self) -> Result<R, TaskPanicked> {
+        let completion = Arc::clone(&self.completion);
+        while !completion.is_finished() {
+            let waiter = Arc::new(Waiter::new(thread::current()));
+            completion.waiters.register(Arc::clone(&waiter));
+            // The task might have finished before the registration became visible.
+            if completion.is_finished() {
+                completion.waiters.cancel(&waiter);
+                break;
+            }
+            while !waiter.is_notified() {
+                thread::park();
+            }
+        }
+        self.take()
+    }
+
+    /// Returns the result if the task has finished, and hands the handle back otherwise.
+    pub fn try_join(mut self) -> Result<Result<R, TaskPanicked>, Self> {
+        if self.is_finished() {
+            Ok(self.take())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Returns whether the task has finished, in which case joining does not block.
+    pub fn is_finished(&self) -> bool {
+        self.completion.is_finished()
+    }
+
+    // Takes the result of a finished task. It can only be taken once.
+    fn take(&mut self) -> Result<R, TaskPanicked> {
+        let result = unsafe { (*self.completion.result.get()).take() };
+        result
+            .expect("The result is only taken once")
+            .map_err(TaskPanicked)
+    }
+}
+
+impl<R> Future for TaskHandle<R> {
+    type Output = Result<R, TaskPanicked>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.is_finished() {
+            return Poll::Ready(this.take());
+        }
+        if let Some(waiter) = &this.waiter
+            && waiter.will_wake(cx.waker())
+        {
+            return Poll::Pending;
+        }
+        if let Some(waiter) = this.waiter.take() {
+            this.completion.waiters.cancel(&waiter);
+        }
+        let waiter = Arc::new(Waiter::with_waker(cx.waker().clone()));
+        this.completion.waiters.register(Arc::clone(&waiter));
+        // The task might have finished before the registration became visible.
+        if this.is_finished() {
+            this.completion.waiters.cancel(&waiter);
+            return Poll::Ready(this.take());
+        }
+        this.waiter = Some(waiter);
+        Poll::Pending
+    }
+}
+
+// The number of times an idle worker looks for a task before it goes to sleep.
+const SPINS: usize = 64;
+
+thread_local! {
+    // The pool that the current thread works for and what it takes its tasks from.
+    static LOCAL: Cell<(*const Shared, *const Local)> =
+        const { Cell::new((ptr::null(), ptr::null())) };
+}
+
+// Parks until the condition holds, registered on a list that gets notified whenever it might
+// have come true. Gives up at the deadline, if there is one, and returns whether it holds.
+fn wait_until(list: &WaitList, condition: impl Fn() -> bool, deadline: Option<Instant>) -> bool {
+    loop {
+        if condition() {
+            return true;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return false;
+        }
+        let waiter = Arc::new(Waiter::new(thread::current()));
+        list.register(Arc::clone(&waiter));
+        // The condition might have come true before the registration became visible.
+        if condition() {
+            list.cancel(&waiter);
+            return true;
+        }
+        while !waiter.is_notified() {
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        list.cancel(&waiter);
+                        break;
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+                None => thread::park(),
+            }
+        }
+    }
+}
+
+// The state that the workers share with the pool.
+struct Shared {
+    // The only queue of the pool, or the injector that takes the tasks from outside of the pool
+    // in work-stealing mode.
+    tasks: Queue<Task>,
+    // The stealing ends of the local queues of the workers in work-stealing mode.
+    stealers: Vec<Stealer<Task>>,
+    // The workers that went to sleep for the lack of tasks. Every new task wakes up one of them.
+    sleepers: WaitList,
+    // The workers that have been started and have not exited yet.
+    alive: AtomicUsize,
+    // The workers that found nothing to do and are committed to sleeping. A worker counts
+    // itself as idle only after its last look for a task, and stops counting before its next
+    // one, so a task that is still queued always shows up in the queue next to the count.
+    idle: AtomicUsize,
+    // The threads waiting for the pool to become idle or for the workers to exit. They get
+    // notified whenever a worker goes idle or exits.
+    watchers: WaitList,
+    // Set by a shutdown that drops the tasks which are left instead of running them.
+    discarding: AtomicBool,
+    executed: ShardedCounter,
+    panicked: ShardedCounter,
+    discarded: ShardedCounter,
+    panic_handler: Option<PanicHandler>,
+}
+
+// What a worker takes its tasks from besides the shared queue.
+struct Local {
+    index: usize,
+    // The local queue in work-stealing mode.
+    worker: Option<deque::Worker<Task>>,
+    // The state of the xorshift generator that picks the victims to steal from.
+    seed: Cell<u64>,
+}
+
+impl Local {
+    fn new(index: usize, worker: Option<deque::Worker<Task>>) -> Self {
+        Self {
+            index,
+            worker,
+            seed: Cell::new((index as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15)),
+        }
+    }
+
+    fn random(&self) -> usize {
+        let mut x = self.seed.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.seed.set(x);
+        x as usize
+    }
+}
+
+impl Shared {
+    // Runs a task on a worker, or drops it during a shutdown that discards the rest. Using
+    // AssertUnwindSafe here is fine in order to make the catch_unwind succeed because we are never
+    // operating on the state of the underlying things after the error is caught.
+    fn run(&self, task: Task) {
+        if self.discarding.load(Ordering::Relaxed) {
+            self.discard(task);
+            return;
+        }
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(task)) {
+            self.panicked.increment();
+            if let Some(handler) = &self.panic_handler {
+                // A panicking handler must not take the worker down with it.
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(payload)));
+            }
+        }
+        self.executed.increment();
+    }
+
+    // Drops a task without running it. Submitted and scoped tasks let whoever waits for them
+    // know when they get dropped.
+    fn discard(&self, task: Task) {
+        std::mem::drop(task);
+        self.discarded.increment();
+    }
+
+    // Runs tasks on a worker until the scope is done, so that a scope opened by a task does not
+    // keep the worker from running the tasks of the scope. Whenever there is nothing to run the
+    // worker sleeps on the sleepers and on the scope at the same time, so that either a new task
+    // or the end of the scope wakes it up.
+    fn help(&self, local: &Local, scope: &ScopeState) {
+        let mut spins = 0;
+        while !scope.is_done() {
+            match self.find_task(local) {
+                Ok(task) => {
+                    self.run(task);
+                    spins = 0;
+                    continue;
+                }
+                Err(_) if spins < SPINS => {
+                    spins += 1;
+                    std::hint::spin_loop();
+                    continue;
+                }
+                Err(_) => spins = 0,
+            }
+            let sleeper = Arc::new(Waiter::new(thread::current()));
+            let waiter = Arc::new(Waiter::new(thread::current()));
+            self.sleepers.register(Arc::clone(&sleeper));
+            scope.waiters.register(Arc::clone(&waiter));
+            // Either condition might have come true before the registrations became visible.
+            let task = if scope.is_done() {
+                None
+            } else {
+                self.find_task(local).ok()
+            };
+            if task.is_none() && !scope.is_done() {
+                while !sleeper.is_notified() && !waiter.is_notified() {
+                    thread::park();
+                }
+            }
+            // The end of a scope wakes up everyone, there is nothing to pass on.
+            scope.waiters.cancel(&waiter);
+            if !self.sleepers.cancel(&sleeper) && (task.is_some() || scope.is_done()) {
+                // The notification was meant for a worker that is going to look for a task,
+                // which we are not going to do, so we pass it on to another one.
+                self.sleepers.notify_one();
+            }
+            if let Some(task) = task {
+                self.run(task);
+            }
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::SeqCst) == self.alive.load(Ordering::SeqCst)
+            && self.tasks.is_empty()
+    }
+
+    // Waits for the next task. Returns None once the queue has been closed and no task is left
+    // to be found.
+    //
+    // A worker registers itself as a sleeper and then looks for a task once more before it
+    // parks, the same way as Queue::dequeue_blocking. A task pushed before the registration
+    // became visible is found by that look, and one pushed after it comes with a notification.
+    fn next_task(&self, local: &Local) -> Option<Task> {
+        loop {
+            for _ in 0..SPINS {
+                match self.find_task(local) {
+                    Ok(task) => return Some(task),
+                    Err(DequeueError::ClosedAndEmpty) => return None,
+                    Err(DequeueError::Empty) => std::hint::spin_loop(),
+                }
+            }
+            let waiter = Arc::new(Waiter::new(thread::current()));
+            self.sleepers.register(Arc::clone(&waiter));
+            match self.find_task(local) {
+                Ok(task) => {
+                    if !self.sleepers.cancel(&waiter) {
+                        // The notification was meant for a worker that is going to sleep, so we
+                        // pass it on to another one.
+                        self.sleepers.notify_one();
+                    }
+                    return Some(task);
+                }
+                Err(DequeueError::ClosedAndEmpty) => {
+                    // Closing wakes up everyone, there is nothing to pass on.
+                    self.sleepers.cancel(&waiter);
+                    return None;
+                }
+                Err(DequeueError::Empty) => {}
+            }
+            self.idle.fetch_add(1, Ordering::SeqCst);
+            self.watchers.notify(usize::MAX);
+            while !waiter.is_notified() {
+                thread::park();
+            }
+            self.idle.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // Looks for a task in the local queue first, then in the shared queue and finally in the
+    // local queues of the other workers. A worker only goes to sleep once its local queue is
+    // empty, so the tasks that are left in the local queues after the shared queue has been
+    // closed still get run by their owners.
+    fn find_task(&self, local: &Local) -> Result<Task, DequeueError> {
+        if let Some(worker) = &local.worker
+            && let Some(task) = worker.pop()
+        {
+            return Ok(task);
+        }
+        let closed = match self.tasks.dequeue() {
+            Ok(task) => return Ok(task),
+            Err(error) => error,
+        };
+        self.steal(local).ok_or(closed)
+    }
+
+    // Tries to steal a task from the other workers, starting with a random one.
+    fn steal(&self, local: &Local) -> Option<Task> {
+        let count = self.stealers.len();
+        if count < 2 {
+            return None;
+        }
+        let start = local.random() % count;
+        loop {
+            let mut retry = false;
+            for offset in 0..count {
+                let victim = (start + offset) % count;
+                if victim == local.index {
+                    continue;
+                }
+                match self.stealers[victim].steal() {
+                    Steal::Success(task) => return Some(task),
+                    Steal::Retry => retry = true,
+                    Steal::Empty => {}
+                }
+            }
+            if !retry {
+                return None;
+            }
+        }
+    }
+}
+
+// Counts a worker out when it exits and lets the watchers know.
+struct Exit<'a>(&'a Shared);
+
+impl Drop for Exit<'_> {
+    fn drop(&mut self) {
+        LOCAL.set((ptr::null(), ptr::null()));
+        self.0.alive.fetch_sub(1, Ordering::SeqCst);
+        self.0.watchers.notify(usize::MAX);
+    }
+}
+
+// The state of a scope that its tasks share with it.
+struct ScopeState {
+    // The number of tasks that have not finished yet.
+    pending: AtomicUsize,
+    // The payload of the first task that panicked.
+    panic: Mutex<Option<Box<dyn Any + Send + 'static>>>,
+    waiters: WaitList,
+}
+
+impl ScopeState {
+    fn is_done(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) == 0
+    }
+
+    fn finish(&self, result: thread::Result<()>) {
+        if let Err(payload) = result {
+            let mut panic = self.panic.lock().unwrap_or_else(|e| e.into_inner());
+            panic.get_or_insert(payload);
+        }
+        // SeqCst pairs with the registration of the waiters, see WaitList.
+        if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.waiters.notify(usize::MAX);
+        }
+    }
+
+    // Blocks until all the tasks have finished.
+    fn wait(&self) {
+        wait_until(&self.waiters, || self.is_done(), None);
+    }
+}
+
+// Finishes a scoped task that gets dropped without having run, so that its scope does not wait
+// forever. The scope panics with DISCARDED in that case.
+struct FinishOnDrop(Option<Arc<ScopeState>>);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        if let Some(state) = self.0.take() {
+            state.finish(Err(Box::new(DISCARDED)));
+        }
+    }
+}
+
+impl FinishOnDrop {
+    fn finish(mut self, result: thread::Result<()>) {
+        if let Some(state) = self.0.take() {
+            state.finish(result);
+        }
+    }
+}
+
+/// A scope to spawn tasks that borrow from the stack of the caller of ThreadPool::scope.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    // Invariant over both lifetimes, as in std::thread::Scope.
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl fmt::Debug for Scope<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scope")
+            .field("pending", &self.state.pending.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Spawns a task onto the pool that may borrow anything that outlives the scope. It may
+    /// spawn further tasks through the scope.
+    ///
+    /// A pool whose workers have not been spawned yet runs the task right away on the calling
+    /// thread instead, as nothing else would ever run it.
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        self.state.pending.fetch_add(1, Ordering::SeqCst);
+        let guard = FinishOnDrop(Some(Arc::clone(&self.state)));
+        let task: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            // The function and everything that it borrows is gone by the time the task counts
+            // itself as finished.
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            guard.finish(result);
+        });
+        if self.pool.threads.is_empty() {
+            task();
+            return;
+        }
+        // The scope does not end before the task has finished or has been dropped, so the
+        // borrows outlive the task.
+        let task = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Task>(task) };
+        self.pool.push(task);
+    }
+}
+
+type NameFn = Box<dyn Fn(usize) -> String + Send + Sync>;
+type Hook = Arc<dyn Fn(usize) + Send + Sync>;
+type PanicHandler = Arc<dyn Fn(Box<dyn Any + Send + 'static>) + Send + Sync>;
+
+/// Configures and starts a ThreadPool. Every setting has a default, a pool built right away
+/// gets a worker for every CPU that is available to the process.
+pub struct ThreadPoolBuilder {
+    num_threads: usize,
+    thread_name: Option<NameFn>,
+    stack_size: Option<usize>,
+    on_thread_start: Option<Hook>,
+    on_thread_stop: Option<Hook>,
+    pin_threads: bool,
+    work_stealing: bool,
+    panic_handler: Option<PanicHandler>,
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ThreadPoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadPoolBuilder")
+            .field("num_threads", &self.num_threads)
+            .field("stack_size", &self.stack_size)
+            .field("pin_threads", &self.pin_threads)
+            .field("work_stealing", &self.work_stealing)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// Creates a builder for a pool with a worker for every CPU that is available to the process.
+    pub fn new() -> Self {
+        Self {
+            num_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            thread_name: None,
+            stack_size: None,
+            on_thread_start: None,
+            on_thread_stop: None,
+            pin_threads: false,
+            work_stealing: false,
+            panic_handler: None,
+        }
+    }
+
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Sets the function that names the workers, which gets the index of the worker.
+    pub fn thread_name<F>(mut self, thread_name: F) -> Self
+    where
+        F: Fn(usize) -> String + Send + Sync + 'static,
+    {
+        self.thread_name = Some(Box::new(thread_name));
+        self
+    }
+
+    /// Sets the stack size of the workers in bytes.
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    /// Sets a hook that every worker runs with its index before it takes its first task.
+    pub fn on_thread_start<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_thread_start = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a hook that every worker runs with its index after it has taken its last task.
+    pub fn on_thread_stop<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_thread_stop = Some(Arc::new(hook));
+        self
+    }
+
+    /// Pins every worker to a CPU of its own, going round the CPUs that the process may run on
+    /// if there are more workers than CPUs. This is only supported on Linux and ignored
+    /// elsewhere, as is a failure to pin.
+    pub fn pin_threads(mut self, pin_threads: bool) -> Self {
+        self.pin_threads = pin_threads;
+        self
+    }
+
+    /// Gives every worker a local queue of its own. The tasks that are spawned from inside of a
+    /// worker go to its local queue, where the worker takes them from in LIFO order, while the
+    /// tasks from outside of the pool go to a shared injector queue. Workers that run out of
+    /// tasks steal from the local queues of random other workers.
+    ///
+    /// This suits fork-join workloads, where tasks spawn more tasks, as they are mostly run by
+    /// the worker that spawned them without any contention on a shared queue.
+    pub fn work_stealing(mut self, work_stealing: bool) -> Self {
+        self.work_stealing = work_stealing;
+        self
+    }
+
+    /// Sets a handler that gets the payload of every task that panics without anybody else to
+    /// hand the panic to, such as the tasks of execute_task. The panics of submitted and scoped
+    /// tasks go to their handles and scopes instead. Without a handler such panics are only
+    /// counted, see ShutdownReport.
+    pub fn panic_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(Box<dyn Any + Send + 'static>) + Send + Sync + 'static,
+    {
+        self.panic_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Starts the workers and returns the pool, or the error of the first worker that could not
+    /// be started.
+    pub fn build(self) -> io::Result<ThreadPool> {
+        let mut pool = ThreadPool::with_config(self);
+        pool.spawn_workers()?;
+        Ok(pool)
+    }
+}
+
+/// How a shutdown deals with the tasks that have not run yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Runs all the tasks that are left, which is also what dropping the pool does.
+    Drain,
+    /// Drops the tasks that are left. The tasks that are already running are finished.
+    DiscardPending,
+    /// Runs the tasks that are left until the time runs out and drops the rest after that. The
+    /// workers that are still busy with a task by then are left to finish it on their own.
+    Timeout(Duration),
+}
+
+/// What happened to the tasks of a pool over its life, as returned by ThreadPool::shutdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// The tasks that ran, including the ones that panicked.
+    pub executed: usize,
+    /// The tasks that panicked while running. Panics that were handed to the handle of a
+    /// submitted task or to a scope are not counted.
+    pub panicked: usize,
+    /// The tasks that were dropped without running.
+    pub discarded: usize,
+    /// The workers that were still busy when the timeout ran out and were left running.
+    pub detached: usize,
+}
+
 pub struct ThreadPool {
     threads: Vec<JoinHandle<()>>,
-    tasks: Arc<Queue<Task>>,
-    execution: Arc<AtomicBool>,
+    shared: Arc<Shared>,
+    // The local queues in work-stealing mode, each of which moves into its worker. They are
+    // only taken out through a mutable borrow, the mutex just keeps the pool Sync.
+    locals: Mutex<Vec<Option<deque::Worker<Task>>>>,
+    config: ThreadPoolBuilder,
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        self.execution.store(false, Ordering::Relaxed);
-        let mut counter = 0;
-        for thread in self.threads.drain(..) {
-            if thread.join().is_err() {
-                counter += 1;
-            }
-        }
-        eprintln!("{} threads panicked while joining!", counter);
+        self.stop(ShutdownMode::Drain);
     }
 }
 
 impl ThreadPool {
+    /// Creates a pool of the given number of workers, which only start once spawn is called.
+    /// ThreadPoolBuilder creates pools that are ready to go.
     pub fn new(number: usize) -> ThreadPool {
+        Self::with_config(ThreadPoolBuilder::new().num_threads(number))
+    }
+
+    fn with_config(config: ThreadPoolBuilder) -> ThreadPool {
+        let locals: Vec<_> = if config.work_stealing {
+            (0..config.num_threads)
+                .map(|_| Some(deque::Worker::new()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let stealers = locals.iter().flatten().map(|w| w.stealer()).collect();
         ThreadPool {
-            threads: Vec::with_capacity(number),
-            tasks: Arc::new(Queue::<Task>::new()),
-            execution: Arc::new(AtomicBool::new(true)),
+            threads: Vec::with_capacity(config.num_threads),
+            shared: Arc::new(Shared {
+                tasks: Queue::new(),
+                stealers,
+                sleepers: WaitList::new(),
+                alive: AtomicUsize::new(0),
+                idle: AtomicUsize::new(0),
+                watchers: WaitList::new(),
+                discarding: AtomicBool::new(false),
+                executed: ShardedCounter::new(),
+                panicked: ShardedCounter::new(),
+                discarded: ShardedCounter::new(),
+                panic_handler: config.panic_handler.clone(),
+            }),
+            locals: Mutex::new(locals),
+            config,
         }
     }
 
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+
+    /// Starts the workers that have not been started yet, so calling it again does nothing.
     pub fn spawn(&mut self) {
-        for _ in 0..self.threads.capacity() {
-            let queue: Arc<Queue<Task>> = Arc::clone(&self.tasks);
-            let execution: Arc<AtomicBool> = Arc::clone(&self.execution);
-            let thread = thread::spawn(move || {
-                loop {
-                    if !execution.load(Ordering::Relaxed) {
-                        while let Ok(func) = queue.dequeue() {
-                            // Using AssertUnwindSafe here is fine in order to make the catch_unwind
-                            // succeed because we are never operating on the state of the underlying
-                            // things after the error is caught.
-                            let _ = panic::catch_unwind(AssertUnwindSafe(func));
-                        }
-                        break;
-                    }
-                    if let Ok(func) = queue.dequeue() {
-                        // Using AssertUnwindSafe here is fine in order to make the catch_unwind
-                        // succeed because we are never operating on the state of the underlying
-                        // things after the error is caught.
-                        let _ = panic::catch_unwind(AssertUnwindSafe(func));
-                    } else {
-                        thread::yield_now();
-                    }
+        self.spawn_workers()
+            .expect("Failed to spawn a worker thread");
+    }
+
+    /// Shuts the pool down and returns what happened to its tasks. New tasks can not get in
+    /// anymore from here on, only the tasks that are already in the pool spawn more.
+    pub fn shutdown(mut self, mode: ShutdownMode) -> ShutdownReport {
+        self.stop(mode)
+    }
+
+    /// Blocks until there are no tasks left and all the workers are idle. It must not be called
+    /// from a task of the pool, which would wait for itself, and it only returns for a pool made
+    /// with new once its workers have been spawned.
+    pub fn wait_idle(&self) {
+        wait_until(&self.shared.watchers, || self.shared.is_idle(), None);
+    }
+
+    // Closes the queue, which stops new tasks from getting in while the workers finish the ones
+    // that are already there and exit once it is drained. The sleeping workers have to be woken
+    // up to find out. Stopping a pool that has already been stopped does nothing.
+    fn stop(&mut self, mode: ShutdownMode) -> ShutdownReport {
+        let shared = &*self.shared;
+        if mode == ShutdownMode::DiscardPending {
+            shared.discarding.store(true, Ordering::Relaxed);
+        }
+        shared.tasks.close();
+        shared.sleepers.notify(usize::MAX);
+        let mut detached = 0;
+        if let ShutdownMode::Timeout(timeout) = mode {
+            let deadline = Instant::now() + timeout;
+            let exited = || shared.alive.load(Ordering::SeqCst) == 0;
+            if !wait_until(&shared.watchers, exited, Some(deadline)) {
+                shared.discarding.store(true, Ordering::Relaxed);
+                // A worker that is still busy can not be stopped, so it is left running.
+                let (finished, busy) = self.threads.drain(..).partition(JoinHandle::is_finished);
+                self.threads = finished;
+                detached = busy.len();
+            }
+        }
+        for thread in self.threads.drain(..) {
+            // The workers catch the panics of the tasks, so they can only panic in the hooks.
+            let _ = thread.join();
+        }
+        // Anything left in the queue belongs to a pool whose workers never started or have
+        // given up at the timeout.
+        while let Ok(task) = shared.tasks.dequeue() {
+            std::mem::drop(task);
+            shared.discarded.increment();
+        }
+        ShutdownReport {
+            executed: shared.executed.load(),
+            panicked: shared.panicked.load(),
+            discarded: shared.discarded.load(),
+            detached,
+        }
+    }
+
+    /// Returns the number of workers of the pool.
+    pub fn num_threads(&self) -> usize {
+        self.config.num_threads
+    }
+
+    fn spawn_workers(&mut self) -> io::Result<()> {
+        for index in self.threads.len()..self.config.num_threads {
+            let mut builder = thread::Builder::new();
+            if let Some(thread_name) = &self.config.thread_name {
+                builder = builder.name(thread_name(index));
+            }
+            if let Some(stack_size) = self.config.stack_size {
+                builder = builder.stack_size(stack_size);
+            }
+            let shared = Arc::clone(&self.shared);
+            let on_thread_start = self.config.on_thread_start.clone();
+            let on_thread_stop = self.config.on_thread_stop.clone();
+            let pin_threads = self.config.pin_threads;
+            let locals = self.locals.get_mut().unwrap_or_else(|e| e.into_inner());
+            let worker = locals.get_mut(index).and_then(Option::take);
+            self.shared.alive.fetch_add(1, Ordering::SeqCst);
+            let spawned = builder.spawn(move || {
+                let local = Local::new(index, worker);
+                // Counts the worker out even if one of the hooks panics.
+                let _exit = Exit(&shared);
+                if pin_threads {
+                    affinity::pin(index);
+                }
+                LOCAL.set((Arc::as_ptr(&shared), &local));
+                if let Some(hook) = on_thread_start {
+                    hook(index);
+                }
+                while let Some(task) = shared.next_task(&local) {
+                    shared.run(task);
+                }
+                if let Some(hook) = on_thread_stop {
+                    hook(index);
                 }
             });
-            self.threads.push(thread);
+            match spawned {
+                Ok(thread) => self.threads.push(thread),
+                Err(error) => {
+                    self.shared.alive.fetch_sub(1, Ordering::SeqCst);
+                    return Err(error);
+                }
+            }
         }
+        Ok(())
     }
 
     pub fn execute_task<T>(&self, task: T)
     where
         T: FnOnce() + Send + 'static,
     {
-        let boxed = Box::new(task);
-        self.tasks.enqueue(boxed);
+        self.push(Box::new(task));
+    }
+
+    fn push(&self, task: Task) {
+        if let Some(local) = self.current_worker()
+            && let Some(worker) = &local.worker
+        {
+            // We are running on a worker of this pool, which is the only thread that pushes to
+            // its local queue.
+            worker.push(task);
+        } else if let Err(Closed(task)) = self.shared.tasks.enqueue(task) {
+            // The queue only gets closed once the pool shuts down, which can not happen while it
+            // is borrowed here. Should a task get in the way of a shutdown anyway, it is dropped
+            // like the discarded ones so that whoever waits for it finds out.
+            self.shared.discard(task);
+            return;
+        }
+        self.shared.sleepers.notify_one();
+    }
+
+    // Returns what the current thread takes its tasks from, if it is a worker of this pool.
+    fn current_worker(&self) -> Option<&Local> {
+        let (shared, local) = LOCAL.get();
+        // The worker clears its entry before its Local goes away.
+        (shared == Arc::as_ptr(&self.shared)).then(|| unsafe { &*local })
+    }
+
+    /// Runs the function with a scope that spawns tasks onto the pool, like std::thread::scope.
+    /// The tasks may borrow anything that outlives the scope, since this only returns once all of
+    /// them have finished. If the function or any of the tasks panicked, the panic is resumed
+    /// after that, the one of the function taking precedence. Tasks that a shutdown discards
+    /// count as having panicked.
+    ///
+    /// A worker of the pool that opens a scope runs the tasks of the pool while it waits, so that
+    /// nested scopes can not starve the pool of workers. It sleeps once there is nothing to run.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                pending: AtomicUsize::new(0),
+                panic: Mutex::new(None),
+                waiters: WaitList::new(),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        match self.current_worker() {
+            Some(local) => self.shared.help(local, &scope.state),
+            None => scope.state.wait(),
+        }
+        let panicked = scope
+            .state
+            .panic
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        match (result, panicked) {
+            (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+            (Ok(value), None) => value,
+        }
+    }
+
+    /// Runs the function on the pool and returns a handle to its result. A panic in the function
+    /// is caught and handed to whoever joins the handle.
+    pub fn submit<F, R>(&self, f: F) -> TaskHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let completion = Arc::new(Completion {
+            finished: AtomicBool::new(false),
+            result: UnsafeCell::new(None),
+            waiters: WaitList::new(),
+        });
+        let guard = CompleteOnDrop(Some(Arc::clone(&completion)));
+        self.execute_task(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            guard.complete(result);
+        });
+        TaskHandle {
+            completion,
+            waiter: None,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod affinity {
+    use std::ffi::c_ulong;
+
+    const BITS: usize = c_ulong::BITS as usize;
+
+    // The cpu_set_t of glibc and musl, with room for 1024 CPUs.
+    #[repr(C)]
+    struct CpuSet([c_ulong; 1024 / BITS]);
+
+    impl CpuSet {
+        fn empty() -> Self {
+            Self([0; 1024 / BITS])
+        }
+
+        fn contains(&self, cpu: usize) -> bool {
+            self.0[cpu / BITS] & (1 << (cpu % BITS)) != 0
+        }
+    }
+
+    unsafe extern "C" {
+        fn sched_getaffinity(pid: i32, size: usize, mask: *mut CpuSet) -> i32;
+        fn sched_setaffinity(pid: i32, size: usize, mask: *const CpuSet) -> i32;
+    }
+
+    // Pins the calling thread to the CPU at the index among the ones that it may run on. Returns
+    // whether that worked.
+    pub(super) fn pin(index: usize) -> bool {
+        let mut allowed = CpuSet::empty();
+        // A pid of 0 stands for the calling thread.
+        if unsafe { sched_getaffinity(0, size_of::<CpuSet>(), &mut allowed) } != 0 {
+            return false;
+        }
+        let cpus: Vec<usize> = (0..1024).filter(|&cpu| allowed.contains(cpu)).collect();
+        if cpus.is_empty() {
+            return false;
+        }
+        let cpu = cpus[index % cpus.len()];
+        let mut mask = CpuSet::empty();
+        mask.0[cpu / BITS] |= 1 << (cpu % BITS);
+        unsafe { sched_setaffinity(0, size_of::<CpuSet>(), &mask) == 0 }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod affinity {
+    pub(super) fn pin(_index: usize) -> bool {
+        false
     }
 }
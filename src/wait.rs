@@ -0,0 +1,200 @@
+use crate::sync::atomic::{AtomicPtr, AtomicUsize, fence};
+use crate::{BoxedPointer, Doer, Holder};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::Waker;
+use std::thread::Thread;
+
+static DROPBOX: BoxedPointer = BoxedPointer::new();
+
+// States of a waiter
+const WAITING: usize = 0;
+const NOTIFIED: usize = 1;
+const CANCELLED: usize = 2;
+
+// What gets woken up once a waiter is notified.
+enum Wake {
+    Thread(Thread),
+    Task(Waker),
+}
+
+/// A thread or a task waiting on a wait list. The state can only move away from WAITING once, so
+/// whoever moves it decides whether the waiter has been notified or has given up.
+pub(crate) struct Waiter {
+    state: AtomicUsize,
+    wake: Wake,
+}
+
+impl Waiter {
+    pub(crate) fn new(thread: Thread) -> Self {
+        Self {
+            state: AtomicUsize::new(WAITING),
+            wake: Wake::Thread(thread),
+        }
+    }
+
+    pub(crate) fn with_waker(waker: Waker) -> Self {
+        Self {
+            state: AtomicUsize::new(WAITING),
+            wake: Wake::Task(waker),
+        }
+    }
+
+    /// Returns whether the waiter is still waiting and would wake up the same task as the given
+    /// waker, in which case registering again is unnecessary.
+    pub(crate) fn will_wake(&self, waker: &Waker) -> bool {
+        match &self.wake {
+            Wake::Task(registered) => {
+                self.state.load(Ordering::Acquire) == WAITING && registered.will_wake(waker)
+            }
+            Wake::Thread(_) => false,
+        }
+    }
+
+    pub(crate) fn is_notified(&self) -> bool {
+        self.state.load(Ordering::Acquire) == NOTIFIED
+    }
+
+    fn notify(&self) -> bool {
+        if self
+            .state
+            .compare_exchange(WAITING, NOTIFIED, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            match &self.wake {
+                Wake::Thread(thread) => thread.unpark(),
+                Wake::Task(waker) => waker.wake_by_ref(),
+            }
+            return true;
+        }
+        false
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::Acquire) == CANCELLED
+    }
+}
+
+struct Entry {
+    waiter: Arc<Waiter>,
+    next: AtomicPtr<Entry>,
+}
+
+/// A lock-free list of waiters, kept as a stack of entries. Waiters that give up are only marked
+/// as cancelled and get unlinked later, either by a notifier popping them or by a new waiter
+/// registering on top of them.
+///
+/// The count of waiting entries is what keeps notifying cheap. It is read after a SeqCst fence
+/// by the notifier and bumped before a SeqCst fence by the waiter, so either the notifier sees
+/// the waiter or the waiter sees whatever the notifier published before notifying.
+pub(crate) struct WaitList {
+    head: AtomicPtr<Entry>,
+    waiting: AtomicUsize,
+}
+
+impl Drop for WaitList {
+    fn drop(&mut self) {
+        let mut current = self.head.load(Ordering::Acquire);
+        while !current.is_null() {
+            let next = unsafe { (*current).next.load(Ordering::Acquire) };
+            std::mem::drop(unsafe { Box::from_raw(current) });
+            current = next;
+        }
+    }
+}
+
+impl WaitList {
+    pub(crate) fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(std::ptr::null_mut()),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Registers the waiter. The caller has to check the condition it is waiting for once more
+    /// after this returns and before parking, otherwise a notification might have been missed.
+    pub(crate) fn register(&self, waiter: Arc<Waiter>) {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        while self.pop_if(Waiter::is_cancelled).is_some() {}
+        let entry = Box::into_raw(Box::new(Entry {
+            waiter,
+            next: AtomicPtr::new(std::ptr::null_mut()),
+        }));
+        let mut current = self.head.load(Ordering::Acquire);
+        loop {
+            unsafe { (*entry).next.store(current, Ordering::Relaxed) };
+            match self
+                .head
+                .compare_exchange(current, entry, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(head) => current = head,
+            }
+        }
+        fence(Ordering::SeqCst);
+    }
+
+    /// Gives up waiting. Returns false if the waiter has already been notified, in which case
+    /// the caller owns that notification and has to pass it on if it does not act upon it.
+    pub(crate) fn cancel(&self, waiter: &Waiter) -> bool {
+        if waiter
+            .state
+            .compare_exchange(WAITING, CANCELLED, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    /// Notifies at most count waiters. This is only a fence and a load when nobody is waiting.
+    pub(crate) fn notify(&self, count: usize) {
+        fence(Ordering::SeqCst);
+        let mut notified = 0;
+        while notified < count && self.waiting.load(Ordering::Relaxed) > 0 {
+            if let Some(waiter) = self.pop_if(|_| true) {
+                if waiter.notify() {
+                    self.waiting.fetch_sub(1, Ordering::Relaxed);
+                    notified += 1;
+                }
+            } else {
+                return;
+            }
+        }
+    }
+
+    pub(crate) fn notify_one(&self) {
+        self.notify(1);
+    }
+
+    // Pops the entry on top if the predicate holds for its waiter. The entry is protected while it
+    // is looked at and it is retired through the hazard domain once it has been unlinked.
+    fn pop_if<F>(&self, predicate: F) -> Option<Arc<Waiter>>
+    where
+        F: Fn(&Waiter) -> bool,
+    {
+        loop {
+            let mut holder = Holder::default();
+            let guard = unsafe { holder.load_pointer(&self.head) }?;
+            if !predicate(&guard.waiter) {
+                return None;
+            }
+            let next = guard.next.load(Ordering::Acquire);
+            if self
+                .head
+                .compare_exchange(guard.data, next, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                let waiter = Arc::clone(&guard.waiter);
+                let mut swap_holder = Holder::default();
+                let wrapper =
+                    unsafe { swap_holder.get_wrapper(&AtomicPtr::new(guard.data), &DROPBOX) };
+                if let Some(mut wrapper) = wrapper {
+                    wrapper.retire();
+                }
+                return Some(waiter);
+            }
+        }
+    }
+}
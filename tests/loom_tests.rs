@@ -46,6 +46,26 @@ mod queue_test {
             t2.join().unwrap();
         });
     }
+
+    #[test]
+    fn test_close() {
+        use electron::queue::DequeueError;
+        loom::model(|| {
+            let new = Arc::new(Queue::new());
+            let cloned1 = Arc::clone(&new);
+            let cloned2 = Arc::clone(&new);
+            let t1 = loom::thread::spawn(move || cloned1.enqueue(7).is_ok());
+            let t2 = loom::thread::spawn(move || {
+                cloned2.close();
+            });
+            let accepted = t1.join().unwrap();
+            t2.join().unwrap();
+            if accepted {
+                assert_eq!(new.dequeue(), Ok(7));
+            }
+            assert_eq!(new.dequeue(), Err(DequeueError::ClosedAndEmpty));
+        });
+    }
 }
 
 #[cfg(test)]
@@ -89,3 +109,154 @@ mod hazard_test {
         });
     }
 }
+
+#[cfg(test)]
+#[cfg(loom)]
+mod segqueue_test {
+    use electron::SegQueue;
+    use loom::sync::Arc;
+    #[test]
+    fn test_segqueue() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(|| {
+            let new = Arc::new(SegQueue::new());
+            let cloned1 = Arc::clone(&new);
+            let cloned2 = Arc::clone(&new);
+            let t1 = loom::thread::spawn(move || {
+                cloned1.enqueue(7);
+            });
+            let t2 = loom::thread::spawn(move || {
+                let _ = cloned2.dequeue();
+            });
+            t1.join().unwrap();
+            t2.join().unwrap();
+        });
+    }
+}
+
+#[cfg(test)]
+#[cfg(loom)]
+mod deque_test {
+    use electron::deque::{Steal, Worker};
+    #[test]
+    fn test_pop_and_steal() {
+        loom::model(|| {
+            let worker = Worker::new();
+            let stealer = worker.stealer();
+            worker.push(5);
+            let t1 = loom::thread::spawn(move || stealer.steal());
+            let popped = worker.pop();
+            let stolen = t1.join().unwrap();
+            // The last value goes to exactly one of the two.
+            match (popped, stolen) {
+                (Some(5), Steal::Empty | Steal::Retry) | (None, Steal::Success(5)) => {}
+                other => panic!("unexpected outcome {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn test_grow_and_steal() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(|| {
+            let worker = Worker::new();
+            let stealer = worker.stealer();
+            worker.push(1);
+            let t1 = loom::thread::spawn(move || stealer.steal().success());
+            // The buffer only holds a single value under loom, so this replaces it.
+            worker.push(2);
+            let mut values: Vec<i32> = std::iter::from_fn(|| worker.pop()).collect();
+            values.extend(t1.join().unwrap());
+            values.sort();
+            assert_eq!(values, vec![1, 2]);
+        });
+    }
+
+    #[test]
+    fn test_pop_both_ends() {
+        use electron::Deque;
+        use loom::sync::Arc;
+        loom::model(|| {
+            let deque = Arc::new(Deque::new());
+            deque.push_back(5);
+            let cloned = Arc::clone(&deque);
+            let t1 = loom::thread::spawn(move || cloned.pop_front());
+            let back = deque.pop_back();
+            let front = t1.join().unwrap();
+            // The only value goes to exactly one of the two ends.
+            match (front, back) {
+                (Some(5), None) | (None, Some(5)) => {}
+                other => panic!("unexpected outcome {:?}", other),
+            }
+            assert!(deque.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_push_and_pop_other_end() {
+        use electron::Deque;
+        use loom::sync::Arc;
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(|| {
+            let deque = Arc::new(Deque::new());
+            deque.push_back(1);
+            let cloned = Arc::clone(&deque);
+            let t1 = loom::thread::spawn(move || cloned.push_front(2));
+            let back = deque.pop_back();
+            t1.join().unwrap();
+            let mut values: Vec<i32> = std::iter::from_fn(|| deque.pop_front()).collect();
+            values.extend(back);
+            values.sort();
+            assert_eq!(values, vec![1, 2]);
+        });
+    }
+}
+
+#[cfg(test)]
+#[cfg(loom)]
+mod listset_test {
+    use electron::ListSet;
+    use loom::sync::Arc;
+    #[test]
+    fn test_insert_next_to_remove() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(|| {
+            let new = Arc::new(ListSet::new());
+            new.insert(1);
+            new.insert(3);
+            let cloned1 = Arc::clone(&new);
+            let cloned2 = Arc::clone(&new);
+            // The new node goes right behind the one being removed.
+            let t1 = loom::thread::spawn(move || cloned1.insert(2));
+            let t2 = loom::thread::spawn(move || cloned2.remove(&1));
+            assert!(t1.join().unwrap());
+            assert!(t2.join().unwrap());
+            let values: Vec<_> = new.iter().map(|value| *value).collect();
+            assert_eq!(values, vec![2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_remove_adjacent() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(|| {
+            let new = Arc::new(ListSet::new());
+            new.insert(1);
+            new.insert(2);
+            new.insert(3);
+            let cloned1 = Arc::clone(&new);
+            let cloned2 = Arc::clone(&new);
+            let t1 = loom::thread::spawn(move || cloned1.remove(&1));
+            let t2 = loom::thread::spawn(move || cloned2.remove(&2));
+            assert!(t1.join().unwrap());
+            assert!(t2.join().unwrap());
+            let values: Vec<_> = new.iter().map(|value| *value).collect();
+            assert_eq!(values, vec![3]);
+        });
+    }
+}
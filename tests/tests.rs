@@ -19,4 +19,1564 @@ mod stack_test {
             }
         });
     }
+
+    #[test]
+    fn test_len_and_peek() {
+        let new = Stack::new();
+        assert!(new.is_empty());
+        assert_eq!(new.peek_with(|v: &String| v.clone()), None);
+        for i in 0..10 {
+            let _ = new.insert(i.to_string());
+        }
+        assert_eq!(new.len(), 10);
+        assert_eq!(new.peek_with(|v| v.clone()), Some("9".to_string()));
+        assert_eq!(new.delete(), Ok("9".to_string()));
+        assert_eq!(new.len(), 9);
+        assert!(!new.is_empty());
+    }
+
+    #[test]
+    fn test_traits() {
+        let mut new: Stack<String> = (0..3).map(|i| i.to_string()).collect();
+        new.extend(vec!["3".to_string()]);
+        assert_eq!(format!("{:?}", new), r#"["3", "2", "1", "0"]"#);
+        let cloned = new.clone();
+        assert_eq!(new.into_iter().collect::<Vec<_>>(), ["3", "2", "1", "0"]);
+        assert_eq!(cloned.len(), 4);
+        assert_eq!(cloned.delete(), Ok("3".to_string()));
+    }
+}
+
+#[cfg(test)]
+mod queue_test {
+    use electron::Queue;
+    #[test]
+    fn test_batch() {
+        let new = &Queue::new();
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                s.spawn(move || {
+                    new.enqueue_batch((0..100).map(|j| i * 100 + j)).unwrap();
+                });
+            }
+        });
+        let mut values = new.dequeue_batch(250);
+        assert_eq!(values.len(), 250);
+        assert_eq!(new.dequeue_into(&mut values), 550);
+        assert!(new.dequeue_batch(10).is_empty());
+        for i in 0..8 {
+            let batch: Vec<_> = values.iter().filter(|v| **v / 100 == i).copied().collect();
+            assert_eq!(batch, (0..100).map(|j| i * 100 + j).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_batch_concurrent() {
+        let new = &Queue::new();
+        let total = std::sync::atomic::AtomicUsize::new(0);
+        let total = &total;
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(move || {
+                    for _ in 0..50 {
+                        new.enqueue_batch(0..20).unwrap();
+                    }
+                });
+                s.spawn(move || {
+                    let mut taken = 0;
+                    while taken < 1000 {
+                        taken += new.dequeue_batch((1000 - taken).min(7)).len();
+                    }
+                    total.fetch_add(taken, std::sync::atomic::Ordering::Relaxed);
+                });
+            }
+        });
+        assert_eq!(total.load(std::sync::atomic::Ordering::Relaxed), 4000);
+        assert!(new.dequeue().is_err());
+    }
+
+    #[test]
+    fn test_len_and_peek() {
+        let new = &Queue::new();
+        assert!(new.is_empty());
+        assert_eq!(new.peek_with(|v: &String| v.len()), None);
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || {
+                    for j in 0..100 {
+                        new.enqueue(format!("{}-{}", i, j)).unwrap();
+                    }
+                });
+                s.spawn(move || {
+                    for _ in 0..100 {
+                        let _ = new.peek_with(|v| v.len());
+                        let _ = new.dequeue();
+                    }
+                });
+            }
+        });
+        let remaining = new.len();
+        assert_eq!(new.dequeue_batch(usize::MAX).len(), remaining);
+        assert!(new.is_empty());
+        new.enqueue("front".to_string()).unwrap();
+        new.enqueue("back".to_string()).unwrap();
+        assert_eq!(new.peek_with(|v| v.clone()), Some("front".to_string()));
+        assert_eq!(new.len(), 2);
+    }
+
+    #[test]
+    fn test_traits() {
+        let mut new: Queue<String> = (0..3).map(|i| i.to_string()).collect();
+        new.extend(vec!["3".to_string()]);
+        assert_eq!(format!("{:?}", new), r#"["0", "1", "2", "3"]"#);
+        let cloned = new.clone();
+        assert_eq!(new.dequeue(), Ok("0".to_string()));
+        assert_eq!(new.into_iter().collect::<Vec<_>>(), ["1", "2", "3"]);
+        let mut iter = cloned.into_iter();
+        assert_eq!(iter.next(), Some("0".to_string()));
+        std::mem::drop(iter);
+    }
+
+    #[test]
+    fn test_node_pool() {
+        let new = &Queue::with_node_pool(64);
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || {
+                    for j in 0..500 {
+                        new.enqueue(i * 500 + j).unwrap();
+                        let _ = new.dequeue();
+                    }
+                });
+            }
+        });
+        while new.dequeue().is_ok() {}
+        let stats = new.node_pool_stats().unwrap();
+        assert_eq!(stats.hits + stats.misses, 2000);
+        assert!(stats.hits > 0);
+        assert!(stats.idle <= 64);
+        assert!(Queue::<usize>::new().node_pool_stats().is_none());
+    }
+
+    #[test]
+    fn test_blocking() {
+        let new = &Queue::new();
+        let sum = &std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(move || {
+                    for _ in 0..100 {
+                        let value = new.dequeue_blocking().unwrap();
+                        sum.fetch_add(value, std::sync::atomic::Ordering::Relaxed);
+                    }
+                });
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            for i in 0..200 {
+                new.enqueue(i).unwrap();
+            }
+            new.enqueue_batch(200..400).unwrap();
+        });
+        assert_eq!(
+            sum.load(std::sync::atomic::Ordering::Relaxed),
+            (0..400).sum()
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let new = Queue::new();
+        let start = std::time::Instant::now();
+        assert!(
+            new.dequeue_timeout(std::time::Duration::from_millis(50))
+                .is_err()
+        );
+        assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                new.enqueue(1).unwrap();
+            });
+            assert_eq!(
+                new.dequeue_timeout(std::time::Duration::from_secs(10)),
+                Ok(1)
+            );
+        });
+    }
+
+    #[test]
+    fn test_close() {
+        use electron::queue::{Closed, DequeueError};
+        let new = &Queue::new();
+        new.enqueue(1).unwrap();
+        assert!(new.close());
+        assert!(!new.close());
+        assert!(new.is_closed());
+        assert_eq!(new.enqueue(2), Err(Closed(2)));
+        assert_eq!(new.enqueue_batch(3..5), Err(Closed(vec![3, 4])));
+        assert_eq!(new.dequeue(), Ok(1));
+        assert_eq!(new.dequeue(), Err(DequeueError::ClosedAndEmpty));
+        assert_eq!(new.dequeue_blocking(), Err(DequeueError::ClosedAndEmpty));
+        assert_eq!(block_on(new.recv()), None);
+        assert!(new.is_empty());
+        // Every enqueue that went through before the close has to come out of the queue.
+        let new = &Queue::new();
+        let accepted = &std::sync::atomic::AtomicUsize::new(0);
+        let received = &std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || {
+                    for j in 0..1000 {
+                        if new.enqueue(i * 1000 + j).is_ok() {
+                            accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        }
+                    }
+                });
+                s.spawn(move || {
+                    while new.dequeue_blocking().is_ok() {
+                        received.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    }
+                });
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            new.close();
+        });
+        assert_eq!(
+            accepted.load(std::sync::atomic::Ordering::Relaxed),
+            received.load(std::sync::atomic::Ordering::Relaxed)
+        );
+    }
+
+    // Drives a future to completion on the current thread, parking it while the future is
+    // pending. The waker is not one of the crate's own so this also covers foreign executors.
+    pub(super) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        struct Unparker(std::thread::Thread);
+        impl std::task::Wake for Unparker {
+            fn wake(self: std::sync::Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = std::task::Waker::from(std::sync::Arc::new(Unparker(std::thread::current())));
+        let mut context = std::task::Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+            std::thread::park();
+        }
+    }
+
+    #[test]
+    fn test_recv() {
+        let new = &Queue::new();
+        new.enqueue(0).unwrap();
+        assert_eq!(block_on(new.recv()), Some(0));
+        let total = std::sync::atomic::AtomicUsize::new(0);
+        let total = &total;
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(move || {
+                    let mut stream = new.stream();
+                    for _ in 0..100 {
+                        let value = block_on(std::future::poll_fn(|cx| {
+                            std::pin::Pin::new(&mut stream).poll_next(cx)
+                        }))
+                        .unwrap();
+                        total.fetch_add(value, std::sync::atomic::Ordering::Relaxed);
+                    }
+                });
+            }
+            for i in 1..=400 {
+                new.enqueue(i).unwrap();
+            }
+        });
+        assert_eq!(
+            total.load(std::sync::atomic::Ordering::Relaxed),
+            400 * 401 / 2
+        );
+        // A pending future that gets dropped must not keep a notification from others.
+        let waker = std::task::Waker::noop();
+        let mut context = std::task::Context::from_waker(waker);
+        let mut pending = Box::pin(new.recv());
+        assert!(pending.as_mut().poll(&mut context).is_pending());
+        std::thread::scope(|s| {
+            s.spawn(|| new.dequeue_blocking());
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            new.enqueue(1).unwrap();
+            std::mem::drop(pending);
+        });
+    }
+}
+
+#[cfg(test)]
+mod channel_test {
+    use electron::channel::{self, RecvError, RecvTimeoutError, TryRecvError, TrySendError};
+    #[test]
+    fn test_unbounded() {
+        let (tx, rx) = channel::unbounded();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        let total = std::sync::atomic::AtomicUsize::new(0);
+        let total = &total;
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                let tx = tx.clone();
+                s.spawn(move || {
+                    for j in 0..100 {
+                        tx.send(i * 100 + j + 1).unwrap();
+                    }
+                });
+                let rx = rx.clone();
+                s.spawn(move || {
+                    while let Ok(value) = rx.recv() {
+                        total.fetch_add(value, std::sync::atomic::Ordering::Relaxed);
+                    }
+                });
+            }
+            std::mem::drop(tx);
+        });
+        assert_eq!(
+            total.load(std::sync::atomic::Ordering::Relaxed),
+            400 * 401 / 2
+        );
+        assert_eq!(rx.recv(), Err(RecvError));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(
+            rx.recv_timeout(std::time::Duration::from_millis(10)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_disconnection() {
+        let (tx, rx) = channel::unbounded();
+        tx.send(1).unwrap();
+        std::mem::drop(tx);
+        assert!(rx.is_disconnected());
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        let (tx, rx) = channel::unbounded();
+        std::mem::drop(rx);
+        assert!(tx.is_disconnected());
+        assert_eq!(tx.send(1).unwrap_err().0, 1);
+        let (tx, rx) = channel::unbounded::<usize>();
+        assert_eq!(
+            rx.recv_timeout(std::time::Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(rx.recv(), Err(RecvError)));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            std::mem::drop(tx);
+        });
+    }
+
+    #[test]
+    fn test_bounded() {
+        let (tx, rx) = channel::bounded(2);
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 3..200 {
+                    tx.send(i).unwrap();
+                }
+            });
+            for i in 1..200 {
+                assert!(rx.len() <= 2);
+                assert_eq!(rx.recv(), Ok(i));
+            }
+        });
+        // A sender that waits for capacity gives up once the receivers are gone.
+        tx.send(0).unwrap();
+        tx.send(0).unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(tx.send(1).unwrap_err().0, 1));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            std::mem::drop(rx);
+        });
+    }
+
+    #[test]
+    fn test_recv_async() {
+        let (tx, rx) = channel::bounded(4);
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..100 {
+                    tx.send(i).unwrap();
+                }
+            });
+            for i in 0..100 {
+                assert_eq!(super::queue_test::block_on(rx.recv_async()), Ok(i));
+            }
+            assert_eq!(super::queue_test::block_on(rx.recv_async()), Err(RecvError));
+        });
+    }
+}
+
+#[cfg(test)]
+mod bag_test {
+    use electron::Bag;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[test]
+    fn test_local() {
+        let bag = Bag::new();
+        assert!(bag.try_remove_any().is_none());
+        for i in 0..100 {
+            bag.add(i);
+        }
+        assert_eq!(bag.len(), 100);
+        let mut removed: Vec<_> = std::iter::from_fn(|| bag.try_remove_any()).collect();
+        removed.sort();
+        assert_eq!(removed, (0..100).collect::<Vec<_>>());
+        assert!(bag.is_empty());
+        // Drained blocks are swept out as new ones get started.
+        for i in 0..1000 {
+            bag.add(i);
+            assert_eq!(bag.try_remove_any(), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_steal() {
+        let bag = &Bag::new();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    bag.add(i);
+                }
+            });
+        });
+        let mut removed: Vec<_> = std::iter::from_fn(|| bag.try_remove_any()).collect();
+        removed.sort();
+        assert_eq!(removed, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let bag = &Bag::new();
+        let sum = &AtomicUsize::new(0);
+        let count = &AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        bag.add(t * 1000 + i);
+                        if i % 2 == 0
+                            && let Some(value) = bag.try_remove_any()
+                        {
+                            sum.fetch_add(value, Ordering::Relaxed);
+                            count.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        while let Some(value) = bag.try_remove_any() {
+            sum.fetch_add(value, Ordering::Relaxed);
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 8000);
+        assert_eq!(sum.load(Ordering::Relaxed), (0..8000).sum());
+        assert!(bag.is_empty());
+    }
+
+    #[test]
+    fn test_drop() {
+        let value = std::sync::Arc::new(());
+        let bag = Bag::new();
+        for _ in 0..100 {
+            bag.add(value.clone());
+        }
+        drop(bag);
+        assert_eq!(std::sync::Arc::strong_count(&value), 1);
+    }
+}
+
+#[cfg(test)]
+mod counter_test {
+    use electron::{CachePadded, ShardedCounter};
+    #[test]
+    fn test_padding() {
+        let padded = [CachePadded::new(1u8), CachePadded::new(2u8)];
+        assert!(std::mem::align_of::<CachePadded<u8>>() >= 64);
+        let distance = &padded[1] as *const _ as usize - &padded[0] as *const _ as usize;
+        assert!(distance >= 64);
+        assert_eq!(*padded[1], 2);
+        assert_eq!(padded[0].into_inner(), 1);
+    }
+
+    #[test]
+    fn test_counter() {
+        let counter = &ShardedCounter::with_shards(3);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        counter.increment();
+                    }
+                    counter.add(10);
+                    counter.sub(5);
+                });
+            }
+        });
+        assert_eq!(counter.load(), 8 * 1005);
+        assert_eq!(counter.reset(), 8 * 1005);
+        assert_eq!(counter.load(), 0);
+        // A thread may take away from another thread's cell and go below zero on its own.
+        counter.add(5);
+        std::thread::scope(|s| {
+            s.spawn(|| counter.sub(3));
+        });
+        assert_eq!(counter.load(), 2);
+    }
+}
+
+#[cfg(test)]
+mod deque_test {
+    use electron::deque::{Steal, Worker};
+    #[test]
+    fn test_worker() {
+        let worker = Worker::new();
+        let stealer = worker.stealer();
+        assert_eq!(worker.pop(), None);
+        assert_eq!(stealer.steal(), Steal::Empty);
+        // Enough values to make the buffer grow a few times.
+        for i in 0..100 {
+            worker.push(i);
+        }
+        assert_eq!(worker.len(), 100);
+        assert_eq!(worker.pop(), Some(99));
+        assert_eq!(stealer.steal(), Steal::Success(0));
+        assert_eq!(stealer.clone().steal(), Steal::Success(1));
+        assert_eq!(stealer.len(), 97);
+        // The rest gets dropped with the deque.
+        let worker = Worker::new();
+        worker.push(String::from("dropped"));
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let worker = Worker::new();
+        let stolen = std::sync::Mutex::new(Vec::new());
+        let done = std::sync::atomic::AtomicBool::new(false);
+        let mut popped = Vec::new();
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                let stealer = worker.stealer();
+                let (stolen, done) = (&stolen, &done);
+                s.spawn(move || {
+                    let mut local = Vec::new();
+                    loop {
+                        match stealer.steal() {
+                            Steal::Success(value) => local.push(value),
+                            Steal::Retry => {}
+                            Steal::Empty => {
+                                if done.load(std::sync::atomic::Ordering::Acquire) {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    stolen.lock().unwrap().extend(local);
+                });
+            }
+            for i in 0..10000 {
+                worker.push(i);
+                if i % 3 == 0 {
+                    popped.extend(worker.pop());
+                }
+            }
+            while let Some(value) = worker.pop() {
+                popped.push(value);
+            }
+            done.store(true, std::sync::atomic::Ordering::Release);
+        });
+        popped.extend(stolen.into_inner().unwrap());
+        popped.sort();
+        assert_eq!(popped, (0..10000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_deque() {
+        use electron::Deque;
+        let deque = Deque::new();
+        assert_eq!(deque.pop_front(), None);
+        assert_eq!(deque.pop_back(), None);
+        assert!(deque.is_empty());
+        for i in 0..10 {
+            deque.push_back(i);
+            deque.push_front(-i - 1);
+        }
+        assert_eq!(deque.len(), 20);
+        assert_eq!(deque.pop_front(), Some(-10));
+        assert_eq!(deque.pop_back(), Some(9));
+        let mut values: Vec<_> = std::iter::from_fn(|| deque.pop_front()).collect();
+        assert_eq!(values.len(), 18);
+        assert!(values.is_sorted());
+        values.clear();
+        deque.push_front(1);
+        assert_eq!(deque.pop_back(), Some(1));
+        assert!(deque.is_empty());
+        // The rest gets dropped with the deque.
+        let deque = Deque::new();
+        deque.push_back(String::from("dropped"));
+        deque.push_front(String::from("dropped"));
+    }
+
+    #[test]
+    fn test_deque_concurrent() {
+        use electron::Deque;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let deque = &Deque::new();
+        let sum = &AtomicUsize::new(0);
+        let count = &AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        let value = t * 1000 + i;
+                        if t % 2 == 0 {
+                            deque.push_front(value);
+                        } else {
+                            deque.push_back(value);
+                        }
+                        let popped = if i % 2 == 0 {
+                            deque.pop_front()
+                        } else {
+                            deque.pop_back()
+                        };
+                        if let Some(value) = popped {
+                            sum.fetch_add(value, Ordering::Relaxed);
+                            count.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        while let Some(value) = deque.pop_back() {
+            sum.fetch_add(value, Ordering::Relaxed);
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 8000);
+        assert_eq!(sum.load(Ordering::Relaxed), (0..8000).sum());
+        assert!(deque.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod hashmap_test {
+    use electron::{HashMap, Holder};
+    #[test]
+    fn test_map() {
+        let new = HashMap::new();
+        let mut holder = Holder::default();
+        assert!(new.is_empty());
+        assert!(new.get("one", &mut holder).is_none());
+        assert!(new.insert(String::from("one"), 1));
+        assert!(new.insert(String::from("two"), 2));
+        assert_eq!(*new.get("one", &mut holder).unwrap(), 1);
+        // A guard keeps the old value readable after it has been replaced.
+        let old = new.get("two", &mut holder).unwrap();
+        assert!(!new.insert(String::from("two"), 22));
+        assert_eq!(*old, 2);
+        std::mem::drop(old);
+        assert_eq!(*new.get("two", &mut holder).unwrap(), 22);
+        assert_eq!(new.len(), 2);
+        assert!(new.remove("one"));
+        assert!(!new.remove("one"));
+        assert!(!new.contains_key("one"));
+        let value = new.compute_if_absent(String::from("three"), |_| 3, &mut holder);
+        assert_eq!(*value, 3);
+        std::mem::drop(value);
+        let value = new.compute_if_absent(String::from("three"), |_| 33, &mut holder);
+        assert_eq!(*value, 3);
+        std::mem::drop(value);
+        assert_eq!(new.len(), 2);
+        // Enough entries to make the table double a few times.
+        for i in 0..1000 {
+            new.insert(i.to_string(), i);
+        }
+        for i in 0..1000 {
+            assert_eq!(*new.get(&i.to_string(), &mut holder).unwrap(), i);
+        }
+        assert_eq!(new.len(), 1002);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let new = &HashMap::new();
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || {
+                    let mut holder = Holder::default();
+                    for j in 0..1000 {
+                        let key = i * 1000 + j;
+                        assert!(new.insert(key, j));
+                        assert_eq!(*new.get(&key, &mut holder).unwrap(), j);
+                        if j % 2 == 0 {
+                            assert!(new.remove(&key));
+                        }
+                        // Every thread races on the same keys here.
+                        let shared = new.compute_if_absent(j + 10000, |k| *k, &mut holder);
+                        assert_eq!(*shared, j + 10000);
+                    }
+                });
+            }
+        });
+        assert_eq!(new.len(), 3000);
+        for key in 0..4000 {
+            assert_eq!(new.contains_key(&key), key % 2 == 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod listset_test {
+    use electron::ListSet;
+    #[test]
+    fn test_set() {
+        let new = ListSet::new();
+        assert!(new.is_empty());
+        for i in [5, 3, 8, 1, 9] {
+            assert!(new.insert(i));
+        }
+        assert!(!new.insert(3));
+        assert_eq!(new.len(), 5);
+        assert!(new.contains(&8));
+        assert!(new.remove(&8));
+        assert!(!new.remove(&8));
+        assert!(!new.contains(&8));
+        let values: Vec<_> = new.iter().map(|value| *value).collect();
+        assert_eq!(values, vec![1, 3, 5, 9]);
+        // An entry keeps a removed value readable.
+        let first = new.iter().next().unwrap();
+        assert!(new.remove(&1));
+        assert!(first.is_removed());
+        assert_eq!(*first, 1);
+        std::mem::drop(first);
+        let strings: ListSet<String> = ["b", "a"].iter().map(|s| s.to_string()).collect();
+        assert!(strings.contains("a"));
+        assert_eq!(*strings.iter().next().unwrap(), "a");
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let new = &ListSet::new();
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || {
+                    for j in 0..500 {
+                        assert!(new.insert(j * 4 + i));
+                        if j % 2 == 0 {
+                            assert!(new.remove(&(j * 4 + i)));
+                        }
+                    }
+                });
+            }
+            s.spawn(move || {
+                for _ in 0..50 {
+                    let values: Vec<_> = new.iter().map(|value| *value).collect();
+                    assert!(values.windows(2).all(|pair| pair[0] < pair[1]));
+                }
+            });
+        });
+        let values: Vec<_> = new.iter().map(|value| *value).collect();
+        let expected: Vec<_> = (0..2000).filter(|v| (v / 4) % 2 == 1).collect();
+        assert_eq!(values, expected);
+        assert_eq!(new.len(), 1000);
+    }
+}
+
+#[cfg(test)]
+mod pool_test {
+    use electron::ObjectPool;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[test]
+    fn test_pool() {
+        let made = std::sync::Arc::new(AtomicUsize::new(0));
+        let counter = std::sync::Arc::clone(&made);
+        let pool = ObjectPool::with_max_idle(2, move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Vec::<u8>::with_capacity(1024)
+        })
+        .with_reset(|buffer| buffer.clear());
+        let mut first = pool.get();
+        first.extend_from_slice(b"dirty");
+        std::mem::drop(first);
+        assert_eq!(pool.idle(), 1);
+        // The buffer comes back cleaned up but with its allocation.
+        let reused = pool.get();
+        assert!(reused.is_empty());
+        assert!(reused.capacity() >= 1024);
+        assert_eq!(made.load(Ordering::Relaxed), 1);
+        let others: Vec<_> = (0..3).map(|_| pool.get()).collect();
+        assert_eq!(made.load(Ordering::Relaxed), 4);
+        std::mem::drop(reused);
+        std::mem::drop(others);
+        // Only two of the four are kept.
+        assert_eq!(pool.idle(), 2);
+        let detached = pool.get().detach();
+        assert_eq!(pool.idle(), 1);
+        std::mem::drop(detached);
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let pool = &ObjectPool::with_max_idle(4, || vec![0u64; 16]);
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        let mut buffer = pool.get();
+                        // Nobody else may be holding the same buffer.
+                        assert!(buffer.iter().all(|value| *value == 0));
+                        buffer[0] = i;
+                        buffer[0] = 0;
+                    }
+                });
+            }
+        });
+        assert!(pool.idle() <= 4);
+    }
+}
+
+#[cfg(test)]
+mod priorityqueue_test {
+    use electron::PriorityQueue;
+    #[test]
+    fn test_order() {
+        let new = PriorityQueue::new();
+        assert!(new.is_empty());
+        assert_eq!(new.pop_min(), None);
+        for i in [5, 3, 8, 1, 9, 2, 7] {
+            new.insert(i, i.to_string());
+        }
+        // Equal keys come out in insertion order.
+        new.insert(3, "second".to_string());
+        assert_eq!(new.len(), 8);
+        assert_eq!(new.pop_min(), Some((1, "1".to_string())));
+        assert_eq!(new.pop_min(), Some((2, "2".to_string())));
+        assert_eq!(new.pop_min(), Some((3, "3".to_string())));
+        assert_eq!(new.pop_min(), Some((3, "second".to_string())));
+        assert_eq!(new.pop_min(), Some((5, "5".to_string())));
+        assert!(!new.is_empty());
+        // The remaining values get dropped with the queue.
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let new = &PriorityQueue::new();
+        let popped = &std::sync::Mutex::new(Vec::new());
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || {
+                    for j in 0..500 {
+                        new.insert((j * 7 + i * 13) % 1000, i * 500 + j);
+                    }
+                });
+                s.spawn(move || {
+                    let mut local = Vec::new();
+                    for _ in 0..500 {
+                        if let Some((_, value)) = new.pop_min() {
+                            local.push(value);
+                        }
+                    }
+                    popped.lock().unwrap().extend(local);
+                });
+            }
+        });
+        let mut popped = popped.lock().unwrap().clone();
+        let mut last = None;
+        while let Some((key, value)) = new.pop_min() {
+            // Without concurrent inserts the rest comes out sorted.
+            assert!(last <= Some(key));
+            last = Some(key);
+            popped.push(value);
+        }
+        popped.sort();
+        assert_eq!(popped, (0..2000).collect::<Vec<_>>());
+        assert!(new.is_empty());
+    }
+
+    #[test]
+    fn test_heap_keys() {
+        // Owns an allocation, so a key that is dropped while a search still compares against it
+        // shows up.
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+        struct Key(Box<usize>);
+        let new = &PriorityQueue::new();
+        let popped = &std::sync::Mutex::new(Vec::new());
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || {
+                    for j in 0..500 {
+                        new.insert(Key(Box::new(i * 500 + j)), ());
+                    }
+                });
+                s.spawn(move || {
+                    let mut local = Vec::new();
+                    for _ in 0..500 {
+                        if let Some((Key(key), ())) = new.pop_min() {
+                            local.push(*key);
+                        }
+                    }
+                    popped.lock().unwrap().extend(local);
+                });
+            }
+        });
+        let mut popped = popped.lock().unwrap().clone();
+        while let Some((Key(key), ())) = new.pop_min() {
+            popped.push(*key);
+        }
+        popped.sort();
+        assert_eq!(popped, (0..2000).collect::<Vec<_>>());
+    }
+}
+
+#[cfg(test)]
+mod skipmap_test {
+    use electron::SkipMap;
+    #[test]
+    fn test_map() {
+        let new = SkipMap::new();
+        assert!(new.is_empty());
+        assert!(new.first().is_none());
+        for i in [50, 30, 80, 10, 90, 20, 70] {
+            assert!(new.insert(i, i.to_string()));
+        }
+        assert_eq!(new.len(), 7);
+        assert_eq!(new.get(&30).unwrap().value(), "30");
+        assert!(new.get(&40).is_none());
+        // An entry keeps the old value readable after it has been replaced.
+        let old = new.get(&80).unwrap();
+        assert!(!new.insert(80, String::from("eighty")));
+        assert_eq!(old.value(), "80");
+        std::mem::drop(old);
+        assert_eq!(new.get(&80).unwrap().value(), "eighty");
+        assert_eq!(*new.first().unwrap().key(), 10);
+        assert_eq!(*new.last().unwrap().key(), 90);
+        let keys: Vec<_> = new.range(20..70).map(|entry| *entry.key()).collect();
+        assert_eq!(keys, vec![20, 30, 50]);
+        let keys: Vec<_> = new.range(25..=70).map(|entry| *entry.key()).collect();
+        assert_eq!(keys, vec![30, 50, 70]);
+        assert!(new.remove(&50));
+        assert!(!new.remove(&50));
+        let popped = new.pop_first().unwrap();
+        assert_eq!((*popped.key(), popped.value().as_str()), (10, "10"));
+        let keys: Vec<_> = new.iter().map(|entry| *entry.key()).collect();
+        assert_eq!(keys, vec![20, 30, 70, 80, 90]);
+        assert_eq!(new.len(), 5);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let new = &SkipMap::new();
+        let popped = &std::sync::Mutex::new(Vec::new());
+        let removed = &std::sync::Mutex::new(Vec::new());
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || {
+                    let mut local = Vec::new();
+                    for j in 0..500 {
+                        assert!(new.insert(j * 4 + i, j));
+                        // The key might have been popped already.
+                        if j % 2 == 0 && new.remove(&(j * 4 + i)) {
+                            local.push(j * 4 + i);
+                        }
+                    }
+                    removed.lock().unwrap().extend(local);
+                });
+                s.spawn(move || {
+                    let mut local = Vec::new();
+                    for _ in 0..100 {
+                        if let Some(entry) = new.pop_first() {
+                            local.push(*entry.key());
+                        }
+                        // Races with the removals, which take values out from under it.
+                        for entry in [new.first(), new.last()].into_iter().flatten() {
+                            assert!(*entry.key() < 2000);
+                        }
+                        let keys: Vec<_> = new.range(100..200).map(|e| *e.key()).collect();
+                        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+                        assert!(keys.iter().all(|key| (100..200).contains(key)));
+                    }
+                    popped.lock().unwrap().extend(local);
+                });
+            }
+        });
+        let mut keys: Vec<_> = new.iter().map(|entry| *entry.key()).collect();
+        assert_eq!(keys.len(), new.len());
+        // Every key was either removed, popped or is still there, and only one of them.
+        keys.extend(popped.lock().unwrap().iter());
+        keys.extend(removed.lock().unwrap().iter());
+        keys.sort();
+        assert_eq!(keys, (0..2000).collect::<Vec<_>>());
+    }
+}
+
+#[cfg(test)]
+mod tagged_test {
+    use electron::{Holder, TaggedPtr};
+    use std::sync::atomic::Ordering;
+    #[test]
+    fn test_tagged() {
+        let boxed = Box::into_raw(Box::new(7u64));
+        let tagged: TaggedPtr<u64, 2> = TaggedPtr::new(boxed, 1);
+        assert_eq!(tagged.load(Ordering::Acquire), (boxed, 1));
+        // Both the pointer and the tag have to match.
+        assert_eq!(
+            tagged.compare_exchange((boxed, 0), (boxed, 3), Ordering::AcqRel, Ordering::Acquire),
+            Err((boxed, 1))
+        );
+        assert_eq!(
+            tagged.compare_exchange((boxed, 1), (boxed, 3), Ordering::AcqRel, Ordering::Acquire),
+            Ok((boxed, 1))
+        );
+        let mut holder = Holder::default();
+        let (guard, tag) = unsafe { holder.load_tagged(&tagged) };
+        assert_eq!(tag, 3);
+        assert_eq!(*guard.unwrap(), 7);
+        // The tag survives even without a pointer.
+        tagged.store(std::ptr::null_mut(), 2, Ordering::Release);
+        let (guard, tag) = unsafe { holder.load_tagged(&tagged) };
+        assert!(guard.is_none());
+        assert_eq!(tag, 2);
+        std::mem::drop(unsafe { Box::from_raw(boxed) });
+    }
+}
+
+#[cfg(test)]
+mod segqueue_test {
+    use electron::SegQueue;
+    use electron::queue::DequeueError;
+    #[test]
+    fn test_order() {
+        let new = SegQueue::new();
+        assert!(new.is_empty());
+        for i in 0..100 {
+            new.enqueue(i.to_string());
+        }
+        assert!(!new.is_empty());
+        for i in 0..100 {
+            assert_eq!(new.dequeue(), Ok(i.to_string()));
+        }
+        assert_eq!(new.dequeue(), Err(DequeueError::Empty));
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let new = &SegQueue::new();
+        let sum = &std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || {
+                    for j in 0..1000 {
+                        new.enqueue(i * 1000 + j);
+                    }
+                });
+                s.spawn(move || {
+                    let mut taken = 0;
+                    while taken < 1000 {
+                        if let Ok(value) = new.dequeue() {
+                            sum.fetch_add(value, std::sync::atomic::Ordering::Relaxed);
+                            taken += 1;
+                        }
+                    }
+                });
+            }
+        });
+        assert!(new.is_empty());
+        assert_eq!(
+            sum.load(std::sync::atomic::Ordering::Relaxed),
+            (0..4000).sum()
+        );
+    }
+}
+
+#[cfg(test)]
+mod threadpool_test {
+    use electron::threadpool::ThreadPool;
+    use std::time::Duration;
+    #[test]
+    fn test_submit() {
+        let mut pool = ThreadPool::new(4);
+        pool.spawn();
+        let handles: Vec<_> = (0..100).map(|i| pool.submit(move || i * 2)).collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+        let handle = pool.submit(|| -> usize { panic!("boom") });
+        let error = handle.join().unwrap_err();
+        assert_eq!(error.to_string(), "The task panicked: boom");
+        // The worker survives the panic.
+        assert_eq!(pool.submit(|| 7).join().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_try_join() {
+        let mut pool = ThreadPool::new(1);
+        pool.spawn();
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let mut handle = pool.submit(move || rx.recv().is_ok());
+        assert!(!handle.is_finished());
+        handle = handle.try_join().unwrap_err();
+        tx.send(()).unwrap();
+        while !handle.is_finished() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(handle.try_join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_await() {
+        let mut pool = ThreadPool::new(2);
+        pool.spawn();
+        let handle = pool.submit(|| {
+            std::thread::sleep(Duration::from_millis(20));
+            String::from("done")
+        });
+        assert_eq!(super::queue_test::block_on(handle).unwrap(), "done");
+        let handle = pool.submit(|| panic!("async boom"));
+        assert!(super::queue_test::block_on(handle).is_err());
+    }
+
+    // The time that the thread has spent on the CPU so far, in clock ticks.
+    #[cfg(target_os = "linux")]
+    fn cpu_ticks(thread: &str) -> u64 {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", thread)).unwrap();
+        // The command might contain spaces, the fields after it do not.
+        let fields: Vec<&str> = stat
+            .rsplit(')')
+            .next()
+            .unwrap()
+            .split_whitespace()
+            .collect();
+        // utime and stime, the 14th and 15th fields with the first two cut off.
+        fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_idle() {
+        let mut pool = ThreadPool::new(2);
+        pool.spawn();
+        // Both tasks wait for each other, so they run on different workers.
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let barrier = barrier.clone();
+                pool.submit(move || {
+                    barrier.wait();
+                    std::fs::read_link("/proc/thread-self")
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+            })
+            .collect();
+        let threads: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        std::thread::sleep(Duration::from_millis(50));
+        let before: u64 = threads.iter().map(|t| cpu_ticks(t)).sum();
+        std::thread::sleep(Duration::from_millis(500));
+        let after: u64 = threads.iter().map(|t| cpu_ticks(t)).sum();
+        // Two spinning workers would burn about 100 ticks at the usual 100 ticks a second.
+        assert!(
+            after - before <= 5,
+            "idle workers used {} ticks",
+            after - before
+        );
+        // The sleeping workers still pick up new tasks.
+        assert_eq!(pool.submit(|| 1).join().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_builder() {
+        use electron::threadpool::ThreadPoolBuilder;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let started = Arc::new(AtomicUsize::new(0));
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let (on_start, on_stop) = (started.clone(), stopped.clone());
+        let mut pool = ThreadPoolBuilder::new()
+            .num_threads(3)
+            .thread_name(|index| format!("worker-{}", index))
+            .stack_size(4 << 20)
+            .on_thread_start(move |_| {
+                on_start.fetch_add(1, Ordering::SeqCst);
+            })
+            .on_thread_stop(move |_| {
+                on_stop.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()
+            .unwrap();
+        assert_eq!(pool.num_threads(), 3);
+        let name = pool.submit(|| std::thread::current().name().map(String::from));
+        assert!(name.join().unwrap().unwrap().starts_with("worker-"));
+        // The workers are already running, so this does not add any.
+        pool.spawn();
+        pool.spawn();
+        while started.load(Ordering::SeqCst) < 3 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        drop(pool);
+        assert_eq!(started.load(Ordering::SeqCst), 3);
+        assert_eq!(stopped.load(Ordering::SeqCst), 3);
+        // Spawning a pool made with new twice does not double it either.
+        let mut pool = ThreadPool::new(2);
+        pool.spawn();
+        pool.spawn();
+        let handles: Vec<_> = (0..20)
+            .map(|_| {
+                pool.submit(|| {
+                    std::thread::sleep(Duration::from_millis(1));
+                    std::thread::current().id()
+                })
+            })
+            .collect();
+        let workers: std::collections::HashSet<_> =
+            handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert!(workers.len() <= 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pin_threads() {
+        use electron::threadpool::ThreadPoolBuilder;
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(2)
+            .pin_threads(true)
+            .build()
+            .unwrap();
+        let allowed = pool.submit(|| {
+            let status = std::fs::read_to_string("/proc/thread-self/status").unwrap();
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+                .map(|list| list.trim().to_string())
+                .unwrap()
+        });
+        // A single CPU rather than a range or a list.
+        let allowed = allowed.join().unwrap();
+        assert!(allowed.parse::<usize>().is_ok(), "allowed on {}", allowed);
+    }
+
+    // Splits the count in half until it gets to one, spawning a task for every half.
+    fn fork(pool: std::sync::Arc<ThreadPool>, count: usize, done: std::sync::mpsc::Sender<usize>) {
+        if count == 1 {
+            done.send(1).unwrap();
+            return;
+        }
+        for half in [count / 2, count - count / 2] {
+            let (cloned, done) = (pool.clone(), done.clone());
+            pool.execute_task(move || fork(cloned, half, done));
+        }
+    }
+
+    #[test]
+    fn test_work_stealing() {
+        use electron::threadpool::ThreadPoolBuilder;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let pool = Arc::new(
+            ThreadPoolBuilder::new()
+                .num_threads(4)
+                .work_stealing(true)
+                .build()
+                .unwrap(),
+        );
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cloned = pool.clone();
+        pool.execute_task(move || fork(cloned, 1000, tx));
+        assert_eq!(rx.iter().sum::<usize>(), 1000);
+        let results: Vec<_> = (0..100).map(|i| pool.submit(move || i)).collect();
+        let results: Vec<_> = results.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, (0..100).collect::<Vec<_>>());
+        // The tasks spawned by a worker are still run when the pool is dropped right after.
+        let counter = Arc::new(AtomicUsize::new(0));
+        let (cloned, shared) = (pool.clone(), counter.clone());
+        let root = pool.submit(move || {
+            for _ in 0..100 {
+                let shared = shared.clone();
+                cloned.execute_task(move || {
+                    shared.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        });
+        root.join().unwrap();
+        // The last tasks might still be letting go of their clones of the pool.
+        while Arc::strong_count(&pool) > 1 {
+            std::thread::yield_now();
+        }
+        drop(Arc::into_inner(pool).unwrap());
+        assert_eq!(counter.load(Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    fn test_scope() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let mut pool = ThreadPool::new(3);
+        pool.spawn();
+        let values: Vec<usize> = (0..1000).collect();
+        let total = AtomicUsize::new(0);
+        let returned = pool.scope(|s| {
+            for chunk in values.chunks(100) {
+                let total = &total;
+                s.spawn(move || {
+                    total.fetch_add(chunk.iter().sum(), Ordering::Relaxed);
+                });
+            }
+            "returned"
+        });
+        assert_eq!(returned, "returned");
+        assert_eq!(total.load(Ordering::Relaxed), (0..1000).sum());
+        // Tasks spawn more tasks through the scope and write to the stack of the caller.
+        let mut slots = vec![0; 8];
+        pool.scope(|s| {
+            for (i, slot) in slots.iter_mut().enumerate() {
+                s.spawn(move || {
+                    s.spawn(|| std::thread::sleep(Duration::from_millis(1)));
+                    *slot = i * 2;
+                });
+            }
+        });
+        assert_eq!(slots, (0..8).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_scope_panic() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let mut pool = ThreadPool::new(2);
+        pool.spawn();
+        let finished = AtomicUsize::new(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| panic!("scoped boom"));
+                for _ in 0..10 {
+                    s.spawn(|| {
+                        std::thread::sleep(Duration::from_millis(1));
+                        finished.fetch_add(1, Ordering::Relaxed);
+                    });
+                }
+            })
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"scoped boom"));
+        // The scope still waited for all of its tasks.
+        assert_eq!(finished.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn test_nested_scope() {
+        use electron::threadpool::ThreadPoolBuilder;
+        use std::sync::Arc;
+        for work_stealing in [false, true] {
+            // A single worker that waits in a scope has to run the tasks of the scope itself.
+            let pool = Arc::new(
+                ThreadPoolBuilder::new()
+                    .num_threads(1)
+                    .work_stealing(work_stealing)
+                    .build()
+                    .unwrap(),
+            );
+            let cloned = pool.clone();
+            let sum = pool.submit(move || {
+                let values = [1, 2, 3];
+                let sum = std::sync::atomic::AtomicUsize::new(0);
+                cloned.scope(|s| {
+                    for value in &values {
+                        let sum = &sum;
+                        s.spawn(move || {
+                            sum.fetch_add(*value, std::sync::atomic::Ordering::Relaxed);
+                        });
+                    }
+                });
+                sum.into_inner()
+            });
+            assert_eq!(sum.join().unwrap(), 6);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_scope_sleeps() {
+        use std::sync::Arc;
+        use std::sync::mpsc;
+        let mut pool = ThreadPool::new(2);
+        pool.spawn();
+        let pool = Arc::new(pool);
+        let cloned = pool.clone();
+        let (opener, waiting) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+        let handle = pool.submit(move || {
+            cloned.scope(|s| {
+                let (started, running) = mpsc::channel();
+                s.spawn(move || {
+                    started.send(()).unwrap();
+                    blocked.recv().unwrap();
+                });
+                // The task runs on the other worker, so this one has nothing to do but wait.
+                running.recv().unwrap();
+                let thread = std::fs::read_link("/proc/thread-self").unwrap();
+                opener.send(thread.to_string_lossy().into_owned()).unwrap();
+            });
+        });
+        let thread = waiting.recv().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        let before = cpu_ticks(&thread);
+        std::thread::sleep(Duration::from_millis(500));
+        let after = cpu_ticks(&thread);
+        assert!(
+            after - before <= 5,
+            "the waiting worker used {} ticks",
+            after - before
+        );
+        release.send(()).unwrap();
+        handle.join().unwrap();
+        // The task drops its clone of the pool after it has completed.
+        while Arc::strong_count(&pool) > 1 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_scope_without_workers() {
+        let pool = ThreadPool::new(2);
+        let mut values = vec![0; 4];
+        pool.scope(|s| {
+            for (i, value) in values.iter_mut().enumerate() {
+                s.spawn(move || *value = i);
+            }
+        });
+        assert_eq!(values, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_shutdown_drain() {
+        use electron::threadpool::{ShutdownMode, ShutdownReport};
+        let mut pool = ThreadPool::new(2);
+        pool.spawn();
+        for i in 0..100 {
+            pool.execute_task(move || {
+                if i % 10 == 0 {
+                    panic!("boom");
+                }
+            });
+        }
+        let report = pool.shutdown(ShutdownMode::Drain);
+        let expected = ShutdownReport {
+            executed: 100,
+            panicked: 10,
+            discarded: 0,
+            detached: 0,
+        };
+        assert_eq!(report, expected);
+    }
+
+    #[test]
+    fn test_shutdown_discard_pending() {
+        use electron::threadpool::ShutdownMode;
+        let mut pool = ThreadPool::new(1);
+        pool.spawn();
+        let (started, running) = std::sync::mpsc::channel();
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let first = pool.submit(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+        });
+        let handles: Vec<_> = (0..10).map(|i| pool.submit(move || i)).collect();
+        running.recv().unwrap();
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            release.send(()).unwrap();
+        });
+        let report = pool.shutdown(ShutdownMode::DiscardPending);
+        releaser.join().unwrap();
+        assert_eq!((report.executed, report.discarded), (1, 10));
+        first.join().unwrap();
+        for handle in handles {
+            let error = handle.join().unwrap_err();
+            assert!(error.is_discarded());
+            assert_eq!(error.to_string(), "The task was discarded before it ran");
+        }
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        use electron::threadpool::ShutdownMode;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let mut pool = ThreadPool::new(1);
+        pool.spawn();
+        let stop = Arc::new(AtomicBool::new(false));
+        let cloned = stop.clone();
+        pool.execute_task(move || {
+            while !cloned.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+        let handle = pool.submit(|| 1);
+        let report = pool.shutdown(ShutdownMode::Timeout(Duration::from_millis(50)));
+        assert_eq!((report.discarded, report.detached), (1, 1));
+        assert!(handle.join().unwrap_err().is_discarded());
+        stop.store(true, Ordering::Relaxed);
+        // A pool that finishes in time is joined as usual.
+        let mut pool = ThreadPool::new(2);
+        pool.spawn();
+        let handle = pool.submit(|| 1);
+        let report = pool.shutdown(ShutdownMode::Timeout(Duration::from_secs(10)));
+        assert_eq!((report.executed, report.detached), (1, 0));
+        assert_eq!(handle.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_wait_idle() {
+        use electron::threadpool::ThreadPoolBuilder;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        for work_stealing in [false, true] {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(4)
+                .work_stealing(work_stealing)
+                .build()
+                .unwrap();
+            let counter = Arc::new(AtomicUsize::new(0));
+            for _ in 0..1000 {
+                let counter = counter.clone();
+                pool.execute_task(move || {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            pool.wait_idle();
+            assert_eq!(counter.load(Ordering::Relaxed), 1000);
+            // An idle pool returns right away.
+            pool.wait_idle();
+        }
+    }
+
+    #[test]
+    fn test_panic_handler() {
+        use electron::threadpool::{ShutdownMode, ThreadPoolBuilder};
+        use std::sync::{Arc, Mutex};
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let cloned = messages.clone();
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(2)
+            .panic_handler(move |payload| {
+                let message = payload.downcast_ref::<&str>().copied().unwrap_or("");
+                cloned.lock().unwrap().push(message);
+            })
+            .build()
+            .unwrap();
+        pool.execute_task(|| panic!("first"));
+        pool.execute_task(|| panic!("second"));
+        // Submitted tasks hand their panics to their handles instead.
+        assert!(pool.submit(|| panic!("third")).join().is_err());
+        let report = pool.shutdown(ShutdownMode::Drain);
+        assert_eq!((report.executed, report.panicked), (3, 2));
+        let mut messages = messages.lock().unwrap().clone();
+        messages.sort();
+        assert_eq!(messages, vec!["first", "second"]);
+    }
 }